@@ -1,59 +1,165 @@
-
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CollisionType {
-    Solid,  // index 1 or 3
-    Air,    // index 0 or 2
+    Solid,
+    Air,
     Void,   // No block, unspecified behavior
 }
 
 use macroquad::color::*;
+use serde::{Serialize, Deserialize};
 use super::grid::partition::CellData;
+use super::grid::dag::LoadError;
+use crate::globals::{GRAPH, BLOCKS};
 
-#[derive(Debug)]
-struct Block {
+/// One entry of the palette, as written in the definitions file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    // Saves match blocks up by name, so names are unique within a palette
+    name : String,
+    #[serde(with = "rgba")]
     color : Color,
-    collision_type : CollisionType
+    collision_type : CollisionType,
+    #[serde(flatten)]
+    material : Material,
+    // Kept with the block so saves carry it, nothing draws textures yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    texture : Option<String>,
+}
+
+impl Block {
+    // Everything but the name, with colors compared the way they're saved
+    fn same_kind(&self, other:&Self) -> bool {
+        rgba::bytes(self.color) == rgba::bytes(other.color) && self.collision_type == other.collision_type
+            && self.material == other.material && self.texture == other.texture
+    }
 }
 
-pub struct BlockPalette([Block; 4]);
+/// How a block behaves when things touch it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Material {
+    pub density : f32,
+    // Share of the normal impulse that can go into stopping sliding
+    pub friction : f32,
+    // Share of the approach speed bounced back
+    pub restitution : f32,
+}
+impl Material {
+    /// The material of a contact between two blocks, so anything touching ice slides and anything touching rubber bounces
+    pub fn mix(self, other:Self) -> Self {
+        Self {
+            density: 0.,
+            friction: (self.friction * other.friction).sqrt(),
+            restitution: self.restitution.max(other.restitution),
+        }
+    }
+}
+
+// Colors are written as 0-255 rgba
+mod rgba {
+    use macroquad::color::Color;
+    use serde::{Serialize, Deserialize, Serializer, Deserializer};
+    // Rounded rather than truncated like macroquad does, so reading the bytes back gives the same bytes
+    pub fn bytes(color:Color) -> [u8; 4] {
+        [color.r, color.g, color.b, color.a].map(|channel| (channel * 255.).round() as u8)
+    }
+    pub fn serialize<S: Serializer>(color:&Color, serializer:S) -> Result<S::Ok, S::Error> {
+        bytes(*color).serialize(serializer)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer:D) -> Result<Color, D::Error> {
+        let [r, g, b, a] = <[u8; 4]>::deserialize(deserializer)?;
+        Ok(Color::from_rgba(r, g, b, a))
+    }
+}
+
+/// The palette's first block, which edits clear to and normalization trims away
+pub const BLANK_BLOCK: usize = 0;
+// Identity of air, grass, water and stone, the only blocks there were before palettes could change
+pub const LEGACY_PALETTE: u64 = 18118585615552165567;
+
+// Blocks missing from the palette, say from a save made with more of them, draw like this and don't collide
+static UNKNOWN: Block = Block {
+    name: String::new(),
+    color: MAGENTA,
+    collision_type: CollisionType::Void,
+    material: Material { density: 0., friction: 0., restitution: 0. },
+    texture: None,
+};
+
+pub struct BlockPalette(Vec<Block>);
 impl Default for BlockPalette {
+    // Matches data/blocks.json, used when that can't be read
     fn default() -> Self {
-        Self ( [
-                Block {
-                    color : BLANK,
-                    collision_type : CollisionType::Air
-                },
-                Block {
-                    color : GREEN,
-                    collision_type : CollisionType::Solid
-                },
-                Block {
-                    color : BLUE,
-                    collision_type : CollisionType::Air
-                },
-                Block {
-                    color : GRAY,
-                    collision_type : CollisionType::Solid
-                },
-            ]
-        )
+        let block = |name:&str, color, collision_type, density, friction, restitution| Block {
+            name: name.to_string(), color, collision_type, material: Material { density, friction, restitution }, texture: None
+        };
+        Self ( vec![
+            block("air", BLANK, CollisionType::Air, 0., 0., 0.),
+            block("grass", GREEN, CollisionType::Solid, 1., 0.6, 0.1),
+            block("water", BLUE, CollisionType::Air, 1., 0., 0.),
+            block("stone", GRAY, CollisionType::Solid, 2.5, 0.5, 0.05),
+            block("ice", SKYBLUE, CollisionType::Solid, 0.9, 0.02, 0.05),
+            block("rubber", MAROON, CollisionType::Solid, 1.2, 0.9, 0.8),
+            block("sand", BEIGE, CollisionType::Solid, 1.6, 1., 0.),
+        ])
     }
 }
 // This is an insane amount of redirection.
 impl BlockPalette {
+    /// Parses a list of block definitions, the first one being the blank block edits clear to
+    pub fn from_json(data:&[u8]) -> Result<Self, LoadError> {
+        let blocks:Vec<Block> = serde_json::from_slice(data)?;
+        if blocks.is_empty() { return Err(LoadError::Parse("Palette has no blocks".to_string())) }
+        for (index, block) in blocks.iter().enumerate() {
+            if blocks[.. index].iter().any(|other| other.name == block.name) {
+                return Err(LoadError::Parse(format!("Block {} is defined twice", block.name)))
+            }
+        }
+        Ok(Self(blocks))
+    }
+
+    pub fn blocks(&self) -> &[Block] { &self.0 }
+
+    pub fn find(&self, name:&str) -> Option<usize> {
+        self.0.iter().position(|block| block.name == name)
+    }
+
+    /// The block standing for one from another palette, which has to match it in everything but a number added to its name
+    pub fn find_match(&self, block:&Block) -> Option<usize> {
+        self.0.iter().position(|known| known.same_kind(block) && (known.name == block.name || is_numbered(&known.name, &block.name)))
+    }
+
+    // The name itself if nothing has it yet, otherwise the first free numbered version of it
+    fn free_name(&self, name:&str) -> String {
+        if self.find(name).is_none() { return name.to_string() }
+        (2 ..).map(|number| format!("{name} ({number})")).find(|numbered| self.find(numbered).is_none()).unwrap()
+    }
+
+    fn block(&self, index : usize) -> &Block {
+        self.0.get(index).unwrap_or(&UNKNOWN)
+    }
+
+    pub fn block_count(&self) -> usize { self.0.len() }
+
+    /// Whether each block is solid, which is what the world graph's leaves carry
+    pub fn solidity(&self) -> Vec<bool> { (0 .. self.block_count()).map(|index| self.is_solid_index(index)).collect() }
+
     pub fn index_type(&self, index : usize) -> CollisionType {
-        self.0[index].collision_type
+        self.block(index).collision_type
     }
-    
+
     pub fn cell_type(&self, cell: Option<CellData>) -> CollisionType {
         match cell {
             None => CollisionType::Void,
-            Some(cell) => self.index_type(*cell.pointer.pointer)
+            Some(cell) => self.index_type(cell.block)
         }
     }
 
     pub fn color(&self, index : usize) -> Color {
-        self.0[index].color
+        self.block(index).color
+    }
+
+    pub fn material(&self, index : usize) -> Material {
+        self.block(index).material
     }
 
     pub fn is_solid_cell(&self, cell: Option<CellData>) -> bool {
@@ -62,4 +168,79 @@ impl BlockPalette {
     pub fn is_solid_index(&self, index : usize) -> bool {
         matches!(self.index_type(index), CollisionType::Solid)
     }
+
+    /// Stable FNV-1a hash of every block, used by saves to notice they were made with another palette
+    pub fn identity(&self) -> u64 { self.prefix_identity(self.block_count()).unwrap() }
+
+    /// The identity of a palette holding only the first count blocks of this one
+    pub fn prefix_identity(&self, count:usize) -> Option<u64> {
+        let mut hash = 0xcbf29ce484222325_u64;
+        for block in self.0.get(.. count)? {
+            // Hashed the way the definitions file stores colors, so a palette survives a round trip through it
+            let bytes = rgba::bytes(block.color).into_iter().chain([block.collision_type as u8]);
+            for byte in bytes {
+                hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+            }
+        }
+        Some(hash)
+    }
+}
+
+/// Adds a block type while the game is running, giving it a leaf in the world graph if it doesn't have one yet
+pub fn register_block(block:Block) -> usize {
+    let mut graph = GRAPH.write();
+    let mut palette = BLOCKS.write();
+    palette.0.push(block);
+    let index = palette.0.len() - 1;
+    // A leaf made while loading something that used the block before it was known has nothing real in its payload
+    if graph.leaf_count() > index { graph.set_leaf_payload(index, palette.is_solid_index(index)) }
+    while graph.leaf_count() <= index {
+        let solid = palette.is_solid_index(graph.leaf_count());
+        graph.add_leaf(solid);
+    }
+    index
+}
+
+// Whether name is base with a number added the way free_name does it
+fn is_numbered(name:&str, base:&str) -> bool {
+    name.strip_prefix(base).and_then(|rest| rest.strip_prefix(" (")).and_then(|rest| rest.strip_suffix(')'))
+        .is_some_and(|number| number.parse::<u32>().is_ok())
+}
+
+/// The block in the current palette standing for a block from another one, registering it if it's new.
+/// A new block whose name is already taken by something else gets a numbered name.
+pub fn adopt_block(block:&Block) -> usize {
+    let (known, name) = {
+        let palette = BLOCKS.read();
+        (palette.find_match(block), palette.free_name(&block.name))
+    };
+    match known {
+        Some(index) => index,
+        None => register_block(Block { name, ..block.clone() }),
+    }
+}
+
+#[test]
+fn definitions_match_default_palette() {
+    let palette = BlockPalette::from_json(include_bytes!("../../data/blocks.json")).unwrap();
+    let default = BlockPalette::default();
+    assert_eq!(palette.identity(), default.identity());
+    assert_eq!(palette.prefix_identity(4), Some(LEGACY_PALETTE));
+    assert!(palette.blocks().iter().zip(default.blocks()).all(|(a, b)| a.name == b.name));
+    assert!(BlockPalette::from_json(br#"[]"#).is_err());
+}
+
+#[test]
+fn adopted_blocks_keep_their_properties() {
+    let mut palette = BlockPalette::default();
+    let grass = palette.blocks()[1].clone();
+    assert_eq!(palette.find_match(&grass), Some(1));
+    // Sharing a name isn't enough to be the same block
+    let slick = Block { material: Material { friction: 0., ..grass.material }, ..grass.clone() };
+    assert_eq!(palette.find_match(&slick), None);
+    let name = palette.free_name(&slick.name);
+    assert_eq!(name, "grass (2)");
+    palette.0.push(Block { name, ..slick.clone() });
+    assert_eq!(palette.find_match(&slick), Some(palette.block_count() - 1));
+    assert_eq!(palette.free_name("grass"), "grass (3)");
 }
@@ -0,0 +1,140 @@
+use macroquad::math::{Vec2, UVec2};
+use super::{Entity, Normalization};
+use crate::engine::grid::dag::ExternalPointer;
+use crate::globals::{GRAPH, BLOCKS};
+use crate::engine::blocks::BLANK_BLOCK;
+use crate::engine::grid::dag::Index;
+use crate::engine::grid::meta::MetaNode;
+use crate::engine::grid::clipboard::Clipboard;
+use crate::engine::grid::partition::{gate, ZorderPath, center_to_edge};
+use crate::engine::grid::region::RegionEdit;
+use crate::engine::grid::csg::CsgOp;
+
+impl Entity {
+    // Grid lookups expect points in the entity's unrotated frame
+    fn unrotate(&self, world_point:Vec2) -> Vec2 {
+        (world_point - self.location.position).rotate(Vec2::from_angle(-self.rotation)) + self.location.position
+    }
+
+    /// The cell at height under a world point
+    pub fn world_point_to_cell(&self, world_point:Vec2, height:u32) -> Option<UVec2> {
+        gate::point_to_cells(self.location, height, self.unrotate(world_point))[0]
+    }
+
+    fn world_point_to_path(&self, world_point:Vec2, height:u32) -> Option<ZorderPath> {
+        if height > self.location.pointer.height { return None }
+        let cell = self.world_point_to_cell(world_point, height)?;
+        Some(ZorderPath::from_cell(cell, self.location.pointer.height - height))
+    }
+
+    /// Applies edit to every cell at height between the two corners, inclusive
+    pub fn edit_rect(&mut self, corner_a:UVec2, corner_b:UVec2, height:u32, edit:RegionEdit) {
+        let Ok(root) = GRAPH.write().edit_rect(self.location.pointer, corner_a.min(corner_b), corner_a.max(corner_b), height, edit) else {
+            dbg!("Failed to edit region");
+            return;
+        };
+        self.apply_edit(root);
+    }
+
+    /// Repaints the connected region of the block under a world point
+    pub fn flood_fill(&mut self, world_point:Vec2, to:Index) {
+        let Some(seed) = gate::point_to_real_cells(self.location, self.unrotate(world_point))[0] else { return };
+        let path = ZorderPath::from_cell(seed.cell, self.location.pointer.height - seed.pointer.height);
+        let Ok(root) = GRAPH.write().flood_fill(self.location.pointer, path, to) else {
+            dbg!("Failed to flood fill");
+            return;
+        };
+        self.apply_edit(root);
+    }
+
+    /// Copies the quadrant at height under a world point
+    pub fn copy_at(&self, world_point:Vec2, height:u32) -> Option<Clipboard<MetaNode>> {
+        let path = self.world_point_to_path(world_point, height)?;
+        GRAPH.read().copy_subtree(self.location.pointer, path)
+    }
+
+    /// Stamps the clipboard onto the quadrant of its height under a world point, combining the two if an op is given
+    pub fn paste_at(&mut self, world_point:Vec2, clipboard:&Clipboard<MetaNode>, op:Option<CsgOp>) {
+        let Some(path) = self.world_point_to_path(world_point, clipboard.root.height) else { return };
+        let result = match op {
+            None => GRAPH.write().paste_subtree(self.location.pointer, path, clipboard),
+            Some(op) => GRAPH.write().stamp_subtree(self.location.pointer, path, clipboard, op, |block| BLOCKS.read().is_solid_index(block)),
+        };
+        let Ok(root) = result else {
+            dbg!("Failed to paste");
+            return;
+        };
+        self.apply_edit(root);
+    }
+
+    // Where the center of quadrant sits relative to the center of its parent, in the entity's frame
+    fn quadrant_offset(&self, quadrant:u32, quadrant_height:u32) -> Vec2 {
+        let signs = Vec2::new(if quadrant & 0b01 == 0 { -1. } else { 1. }, if quadrant & 0b10 == 0 { -1. } else { 1. });
+        signs * center_to_edge(quadrant_height, self.location.min_cell_length)
+    }
+
+    // Moves the grid's center without moving anything in the world, offset is in the entity's frame
+    pub(super) fn shift_center(&mut self, offset:Vec2) {
+        self.location.position += offset.rotate(self.forward);
+        self.history.shift(offset);
+    }
+
+    /// Makes the grid one level taller with the current contents in quadrant, leaving them where they are in the world
+    pub fn grow(&mut self, quadrant:u32, fill:Index) -> bool {
+        let old_height = self.location.pointer.height;
+        let Ok(root) = GRAPH.write().wrap_root(self.location.pointer, quadrant, fill) else { return false };
+        self.shift_center(-self.quadrant_offset(quadrant, old_height));
+        self.set_root(root);
+        self.commit_checkpoint();
+        true
+    }
+
+    /// Grows the grid toward a world point, so repeated calls eventually cover it
+    pub fn grow_toward(&mut self, world_point:Vec2, fill:Index) -> bool {
+        let local = self.unrotate(world_point) - self.location.position;
+        // The old contents go on the side away from the point
+        let quadrant = (local.x < 0.) as u32 | ((local.y < 0.) as u32) << 1;
+        self.grow(quadrant, fill)
+    }
+
+    /// Shrinks the grid down to the contents of quadrant, leaving them where they are in the world
+    pub fn crop(&mut self, quadrant:u32) -> bool {
+        let Ok(root) = GRAPH.write().crop_root(self.location.pointer, quadrant) else { return false };
+        self.shift_center(self.quadrant_offset(quadrant, root.height));
+        self.set_root(root);
+        self.commit_checkpoint();
+        true
+    }
+
+    /// Crops the grid to the quadrant holding a world point
+    pub fn crop_toward(&mut self, world_point:Vec2) -> bool {
+        let local = self.unrotate(world_point) - self.location.position;
+        let quadrant = (local.x >= 0.) as u32 | ((local.y >= 0.) as u32) << 1;
+        self.crop(quadrant)
+    }
+
+    /// Swaps in the root an edit produced, normalizing it if the entity opted in
+    pub fn apply_edit(&mut self, root:ExternalPointer) {
+        self.location.pointer = root;
+        self.normalize();
+        self.recaclulate_corners();
+        self.recalculate_mass();
+        self.commit_checkpoint();
+    }
+
+    fn normalize(&mut self) {
+        if self.normalization == Normalization::Off { return }
+        let mut graph = GRAPH.write();
+        let blank = graph.leaf(BLANK_BLOCK).unwrap();
+        loop {
+            let start = self.location.pointer;
+            if let Some(quadrant) = graph.trim_quadrant(start, blank) {
+                let root = graph.crop_root(start, quadrant).unwrap();
+                self.shift_center(self.quadrant_offset(quadrant, root.height));
+                self.location.pointer = root;
+            } else if self.normalization == Normalization::Fit && let Some(root) = graph.fit_root(start, blank) {
+                self.location.pointer = root;
+            } else { break }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+use macroquad::math::Vec2;
+use super::{Entity, ExternalPointer};
+use crate::engine::grid::dag::{SparseDirectedGraph, GraphNode};
+use crate::globals::GRAPH;
+
+// Undo steps past this are dropped oldest first
+pub const HISTORY_LIMIT: usize = 64;
+
+// Snapshots remember where their grid was centered relative to the entity's current grid, in the entity's frame.
+// That way undoing an edit doesn't undo the entity's movement since.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Snapshot {
+    pointer: ExternalPointer,
+    center: Vec2,
+}
+
+/// Earlier and undone roots of an entity, each holding references on its tree so the graph keeps it alive
+#[derive(Debug)]
+pub struct EditHistory {
+    undo: VecDeque<Snapshot>,
+    redo: Vec<Snapshot>,
+    // The grid as of the last checkpoint, which becomes an undo step once an edit changes it
+    pending: Option<Snapshot>,
+    limit: usize,
+}
+impl EditHistory {
+    pub fn new(limit:usize) -> Self {
+        Self { undo: VecDeque::new(), redo: Vec::new(), pending: None, limit }
+    }
+
+    pub fn roots(&self) -> impl Iterator<Item = ExternalPointer> + '_ {
+        self.undo.iter().chain(self.redo.iter()).chain(self.pending.iter()).map(|snapshot| snapshot.pointer)
+    }
+
+    /// Same order as roots
+    pub fn roots_mut(&mut self) -> impl Iterator<Item = &mut ExternalPointer> + '_ {
+        self.undo.iter_mut().chain(self.redo.iter_mut()).chain(self.pending.iter_mut()).map(|snapshot| &mut snapshot.pointer)
+    }
+
+    /// Releases every snapshot
+    pub fn clear(&mut self) {
+        let mut graph = GRAPH.write();
+        for snapshot in self.undo.drain(..).chain(self.redo.drain(..)).chain(self.pending.take()) {
+            graph.release(snapshot.pointer.pointer)
+        }
+    }
+
+    // The entity's grid center moved by offset
+    pub(super) fn shift(&mut self, offset:Vec2) {
+        for snapshot in self.undo.iter_mut().chain(self.redo.iter_mut()).chain(self.pending.iter_mut()) { snapshot.center -= offset }
+    }
+
+    fn drop_pending<T: GraphNode>(&mut self, graph:&mut SparseDirectedGraph<T>) {
+        if let Some(pending) = self.pending.take() { graph.release(pending.pointer.pointer) }
+    }
+
+    fn hold<T: GraphNode>(&mut self, graph:&mut SparseDirectedGraph<T>, current:ExternalPointer) {
+        self.drop_pending(graph);
+        graph.retain(current.pointer);
+        self.pending = Some(Snapshot { pointer: current, center: Vec2::ZERO });
+    }
+
+    // The pending snapshot becomes an undo step if the grid moved on from it, dropping anything that could have been redone
+    fn commit<T: GraphNode>(&mut self, graph:&mut SparseDirectedGraph<T>, current:ExternalPointer) {
+        let Some(pending) = self.pending else { return };
+        if pending == (Snapshot { pointer: current, center: Vec2::ZERO }) { return }
+        self.pending = None;
+        for snapshot in self.redo.drain(..) { graph.release(snapshot.pointer.pointer) }
+        self.undo.push_back(pending);
+        if self.undo.len() > self.limit {
+            let oldest = self.undo.pop_front().unwrap();
+            graph.release(oldest.pointer.pointer);
+        }
+    }
+
+    // Swaps current for the top of the undo or redo stack, pushing it onto the other one. References move between
+    // the entity and the stacks, so only the pending snapshot gets released. The returned center is where the grid moves to.
+    fn step<T: GraphNode>(&mut self, graph:&mut SparseDirectedGraph<T>, current:ExternalPointer, backward:bool) -> Option<Snapshot> {
+        self.drop_pending(graph);
+        let target = if backward { self.undo.pop_back()? } else { self.redo.pop()? };
+        self.shift(target.center);
+        let current = Snapshot { pointer: current, center: -target.center };
+        if backward { self.redo.push(current) } else { self.undo.push_back(current) }
+        Some(target)
+    }
+}
+
+impl Entity {
+    /// Holds onto the current grid so the next edits that change it can be undone as one step.
+    /// Nothing is recorded and redo survives if they don't change anything.
+    pub fn checkpoint(&mut self) {
+        self.history.hold(&mut GRAPH.write(), self.location.pointer);
+    }
+
+    // Called after every edit that swaps the root
+    pub(super) fn commit_checkpoint(&mut self) {
+        self.history.commit(&mut GRAPH.write(), self.location.pointer);
+    }
+
+    pub fn undo(&mut self) -> bool { self.step(true) }
+
+    pub fn redo(&mut self) -> bool { self.step(false) }
+
+    fn step(&mut self, backward:bool) -> bool {
+        let Some(target) = self.history.step(&mut GRAPH.write(), self.location.pointer, backward) else { return false };
+        // The history already moved its own centers
+        self.location.position += target.center.rotate(self.forward);
+        self.set_root(target.pointer);
+        true
+    }
+}
+
+#[test]
+fn history_keeps_graph_valid() {
+    use crate::engine::grid::dag::{BasicNode, Index};
+    use crate::engine::grid::partition::ZorderPath;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let mut history = EditHistory::new(2);
+    let mut root = graph.get_root(0, 2).unwrap();
+    let check = |graph:&SparseDirectedGraph<BasicNode>, history:&EditHistory, root:ExternalPointer| {
+        let roots:Vec<_> = history.roots().chain([root]).collect();
+        let report = graph.validate(&roots);
+        assert!(report.is_valid(), "{:?}", report.violations);
+    };
+    let mut states = vec![root];
+    for cell in 0 .. 3 {
+        history.hold(&mut graph, root);
+        // Nothing is recorded until the grid actually changes
+        history.commit(&mut graph, root);
+        assert_eq!(history.undo.len(), cell.min(2) as usize);
+        check(&graph, &history, root);
+        root = graph.set_nodes(root, &[(ZorderPath::root().step_down(cell), Index(1))]).unwrap();
+        history.commit(&mut graph, root);
+        check(&graph, &history, root);
+        states.push(root);
+    }
+    // The first state fell off the end and gave its references back
+    assert_eq!(history.undo.len(), 2);
+    for expected in [states[2], states[1]] {
+        root = history.step(&mut graph, root, true).unwrap().pointer;
+        assert_eq!(root, expected);
+        check(&graph, &history, root);
+    }
+    assert!(history.step(&mut graph, root, true).is_none());
+    root = history.step(&mut graph, root, false).unwrap().pointer;
+    assert_eq!(root, states[2]);
+    check(&graph, &history, root);
+    // A checkpoint that goes nowhere keeps what can be redone, one that does drops it
+    history.hold(&mut graph, root);
+    history.commit(&mut graph, root);
+    assert_eq!(history.redo.len(), 1);
+    history.hold(&mut graph, root);
+    root = graph.set_nodes(root, &[(ZorderPath::root().step_down(3), Index(2))]).unwrap();
+    history.commit(&mut graph, root);
+    assert!(history.redo.is_empty());
+    check(&graph, &history, root);
+}
@@ -1,12 +1,18 @@
 mod render;
 mod movement;
 mod serialization;
+mod editing;
+mod history;
 use serde::{Serialize, Deserialize};
 use macroquad::math::Vec2;
-use crate::engine::grid::dag::ExternalPointer;
+use crate::engine::grid::dag::{ExternalPointer, Compaction};
+use crate::globals::GRAPH;
 use crate::engine::math::Aabb;
 use crate::engine::grid::partition::*;
 use crate::engine::physics::collisions::{Corners, corner_handling};
+use crate::engine::physics::mass::MassProperties;
+use crate::engine::blocks::BLANK_BLOCK;
+pub use history::{EditHistory, HISTORY_LIMIT};
 
 
 #[derive(derive_new::new)]
@@ -24,6 +30,34 @@ impl EntityPool {
     pub fn get_entity(&self, id:ID) -> Option<&Entity> {
         self.entities.iter().find(|entity| entity.id == id)
     }
+    /// The id after the given one in pool order, wrapping around to the first
+    pub fn next_id(&self, id:ID) -> Option<ID> {
+        let position = self.entities.iter().position(|entity| entity.id == id).map_or(0, |index| index + 1);
+        self.entities.get(position).or(self.entities.first()).map(|entity| entity.id)
+    }
+
+    /// Every root held by the pool, anything else holding references has to be passed in alongside these
+    pub fn live_roots(&self) -> Vec<ExternalPointer> {
+        let mut roots:Vec<_> = self.entities.iter().map(|entity| entity.location.pointer).collect();
+        roots.extend(self.entities.iter().flat_map(|entity| entity.history.roots()));
+        roots
+    }
+
+    /// Compacts the global graph, remapping entity roots and any extra roots the caller holds
+    pub fn compact_graph(&mut self, extra:&mut [ExternalPointer]) -> Compaction {
+        let mut roots = self.live_roots();
+        roots.extend(extra.iter().copied());
+        let compaction = GRAPH.write().compact(&roots);
+        // Roots come back in the order live_roots gave them, followed by the extra ones
+        let mut remapped = compaction.roots.iter().copied();
+        // Corners hold blocks rather than leaves, so they don't need recalculating
+        for entity in self.entities.iter_mut() { entity.location.pointer = remapped.next().unwrap() }
+        for entity in self.entities.iter_mut() {
+            for root in entity.history.roots_mut() { *root = remapped.next().unwrap() }
+        }
+        for root in extra.iter_mut() { *root = remapped.next().unwrap() }
+        compaction
+    }
 }
 
 #[derive(Debug, Clone, Copy, derive_new::new, Serialize, Deserialize)]
@@ -40,6 +74,29 @@ impl Location {
 }
 
 pub type ID = u32;
+
+/// How an entity's tree gets reshaped after edits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Normalization {
+    #[default]
+    Off,
+    // Crop down to the only quadrant still holding blocks
+    Trim,
+    // Trim, and also re-root around the center so the blocks stay inside the centered window with room to spare
+    Fit,
+}
+
+/// How an entity takes part in the simulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum BodyType {
+    // Never moves, whatever its velocity says
+    Static,
+    // Moves by its own velocity but collisions don't push it
+    Kinematic,
+    #[default]
+    Dynamic,
+}
+
 // Chunk and store corner locations in u8s?
 pub struct Entity {
     pub id : ID,
@@ -48,10 +105,32 @@ pub struct Entity {
     pub forward: Vec2,
     pub velocity: Vec2,
     pub angular_velocity: f32,
+    pub body_type: BodyType,
     pub corners : Vec<Corners>,
+    // Cached from the tree, anything swapping the root has to recalculate it
+    pub mass : MassProperties,
+    pub normalization : Normalization,
+    pub history : EditHistory,
 }
 impl Entity {
+    pub fn new(id:ID, location:Location, rotation:f32) -> Self {
+        Self {
+            id,
+            location,
+            rotation,
+            forward: Vec2::from_angle(rotation),
+            velocity: Vec2::ZERO,
+            angular_velocity: 0.,
+            body_type: BodyType::Dynamic,
+            corners: corner_handling::tree_corners(location.pointer, location.min_cell_length),
+            mass: MassProperties::from_tree(location.pointer, location.min_cell_length),
+            normalization: Normalization::Off,
+            history: EditHistory::new(HISTORY_LIMIT),
+        }
+    }
+
     pub fn recaclulate_corners(&mut self) { self.corners = corner_handling::tree_corners(self.location.pointer, self.location.min_cell_length) }
+    pub fn recalculate_mass(&mut self) { self.mass = MassProperties::from_tree(self.location.pointer, self.location.min_cell_length) }
     pub fn aabb(&self) -> Option<Aabb> {
         let (mut top_left, mut bottom_right) = self.get_extreme_points()?;
         top_left += -center_to_edge(self.location.pointer.height, self.location.min_cell_length) + self.location.position;
@@ -59,6 +138,15 @@ impl Entity {
         Some(Aabb::from_bounds(top_left, bottom_right))
     }
 
+    /// Covers the entity facing any way while it moves for the given ticks
+    pub fn swept_aabb(&self, ticks:f32) -> Option<Aabb> {
+        let bounds = self.aabb()?;
+        // The corner farthest from the center of rotation stays that far away however the entity spins
+        let reach = [bounds.min(), bounds.max(), Vec2::new(bounds.min().x, bounds.max().y), Vec2::new(bounds.max().x, bounds.min().y)]
+            .into_iter().map(|corner| corner.distance(self.location.position)).fold(0., f32::max);
+        Some(Aabb::new(self.location.position, Vec2::splat(reach)).expand(self.velocity * ticks))
+    }
+
     pub fn get_extreme_points(&self) -> Option<(Vec2, Vec2)> {
         if self.corners.is_empty() {
             return None;
@@ -67,7 +155,7 @@ impl Entity {
         let mut top_left = Vec2::NAN;
         let mut bottom_right = Vec2::NAN;
         
-        self.corners.iter().filter(|corner| *corner.index != 0)
+        self.corners.iter().filter(|corner| corner.block != BLANK_BLOCK)
             .flat_map(|corner| &corner.points)
             .for_each(|pos| {
                 // Update top-left (minimum x and y)
@@ -22,5 +22,6 @@ impl Entity {
     pub fn set_root(&mut self, new_root:ExternalPointer) { 
         self.location.pointer = new_root;
         self.recaclulate_corners();
+        self.recalculate_mass();
     }
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 use super::*;
 use crate::globals::*;
-use crate::engine::grid::dag::Index;
+use crate::engine::grid::diff::Change;
 impl EntityPool {
     pub fn draw_all(&self, rotate:bool, render_dbg:bool) {
         for entity in self.entities.iter() {
@@ -29,13 +29,13 @@ impl Entity {
                     (cell.points[1] - point_offset).rotate(rotation) + self.location.position,
                     (cell.points[2] - point_offset).rotate(rotation) + self.location.position,
                     (cell.points[3] - point_offset).rotate(rotation) + self.location.position
-                ], *cell.index
+                ], cell.block
             )
         }).collect();
-        for (points, index) in points_list {
+        for (points, block) in points_list {
             CAMERA.read().draw_rectangle_from_corners(
                 &points,
-                BLOCKS.color(index),
+                BLOCKS.read().color(block),
                 render_dbg,
             );
         }
@@ -43,7 +43,8 @@ impl Entity {
     
     pub fn draw_outline(&self, color:macroquad::color::Color) {
         let point_offset = center_to_edge(self.location.pointer.height, self.location.min_cell_length);
-        let square = ExternalPointer::new(Index(1), self.location.pointer.height);
+        // A single leaf covering the whole grid, its block doesn't matter and the blank one is always there
+        let square = ExternalPointer::new(GRAPH.read().leaf(BLANK_BLOCK).unwrap(), self.location.pointer.height);
         let corners = corner_handling::tree_corners(square, self.location.min_cell_length)[0].points;
         let points = [
             (corners[0] - point_offset).rotate(self.forward) + self.location.position,
@@ -54,4 +55,21 @@ impl Entity {
         CAMERA.read().draw_outline(&points, color);
     }
 
+    /// Outlines the quadrants a diff of this entity's tree reported
+    pub fn draw_changes(&self, changes:&[Change], color:macroquad::color::Color) {
+        let point_offset = center_to_edge(self.location.pointer.height, self.location.min_cell_length);
+        for change in changes {
+            let Some(height) = self.location.pointer.height.checked_sub(change.path.depth) else { continue };
+            let length = cell_length(height, self.location.min_cell_length);
+            let top_left = change.path.to_cell().as_vec2() * length;
+            let points = [
+                top_left,
+                top_left + Vec2::new(length.x, 0.),
+                top_left + length,
+                top_left + Vec2::new(0., length.y),
+            ].map(|point| (point - point_offset).rotate(self.forward) + self.location.position);
+            CAMERA.read().draw_outline(&points, color);
+        }
+    }
+
 }
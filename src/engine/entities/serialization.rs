@@ -1,46 +1,364 @@
-
-use super::{Entity, EntityPool, Vec2, Location, ID, corner_handling};
+use super::{Entity, EntityPool, Vec2, Location, ID, BodyType};
 use serde::{Serialize, Deserialize};
-use crate::globals::GRAPH;
+use serde_json::Value;
+use crate::globals::{GRAPH, BLOCKS};
+use crate::engine::blocks::{Block, LEGACY_PALETTE, adopt_block};
+use crate::engine::grid::dag::{LoadError, ExternalPointer, Index, Node, table_leaves, validate_table, tree_from_json};
+use crate::engine::grid::meta::MetaNode;
+
+// Binary entities are a version byte, their body type and eight floats of placement and motion, followed by a binary tree
+const ENTITY_MAGIC: [u8; 4] = *b"GENT";
+const ENTITY_VERSION: u8 = 1;
+// The byte a body type is saved as is its index here
+const BODY_TYPES: [BodyType; 3] = [BodyType::Static, BodyType::Kinematic, BodyType::Dynamic];
+
+// Version 2 adds body types, version 3 saves the palette's blocks the way worlds do
+pub const SAVE_VERSION: u32 = 3;
+// Version 2 saves the palette alongside the world, version 3 adds body types
+pub const WORLD_VERSION: u32 = 3;
+// WORLD_MIGRATIONS[n] upgrades a version n + 1 world to version n + 2, worlds have had versions from the start
+const WORLD_MIGRATIONS: [fn(&mut Value); WORLD_VERSION as usize - 1] = [
+    world_v1_to_v2,
+    world_v2_to_v3,
+];
+// MIGRATIONS[n] upgrades a version n save to version n + 1, unversioned saves count as version 0
+const MIGRATIONS: [fn(&mut Value); SAVE_VERSION as usize] = [
+    unversioned_to_v1,
+    v1_to_v2,
+    v2_to_v3,
+];
+
+fn unversioned_to_v1(save:&mut Value) {
+    // Everything saved before versioning used the default palette and unit cells, back when it had four blocks
+    save["leaf_count"] = 4.into();
+    save["palette"] = LEGACY_PALETTE.into();
+    save["min_cell_length"] = serde_json::json!([1.0, 1.0]);
+}
+
+fn v1_to_v2(save:&mut Value) {
+    // Which entity stayed still used to be decided by main, so everything saved is a mover
+    save["body_type"] = serde_json::to_value(BodyType::Dynamic).unwrap();
+}
+
+fn v2_to_v3(save:&mut Value) {
+    // Saves without their blocks fall back on the palette identity
+    save["blocks"] = Value::Array(Vec::new());
+}
+
+fn world_v1_to_v2(world:&mut Value) {
+    // Worlds without their palette were made with the one the game has
+    world["blocks"] = Value::Array(Vec::new());
+}
+
+fn world_v2_to_v3(world:&mut Value) {
+    // Entities in worlds get the same treatment as ones saved on their own
+    for entity in world["entities"].as_array_mut().into_iter().flatten() { v1_to_v2(entity) }
+}
+
+// Brings a save up to date, migrations[n] upgrading version first + n, missing versions counting as 0
+fn run_migrations(mut save:Value, migrations:&[fn(&mut Value)], first:u64) -> Result<Value, LoadError> {
+    if !save.is_object() { return Err(LoadError::Parse("Save isn't an object".to_string())) }
+    let latest = first + migrations.len() as u64;
+    let version = save.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > latest { return Err(LoadError::UnsupportedVersion(version)) }
+    if version < first { return Err(LoadError::Parse(format!("Save version {version} was never written"))) }
+    for migration in &migrations[(version - first) as usize ..] { migration(&mut save) }
+    save["version"] = latest.into();
+    Ok(save)
+}
+
+// The block each leaf of a save stands for. Saves carrying their blocks get them matched up by name and properties,
+// the rest use leaf n for block n, which only holds if this palette starts with the one they were made with.
+fn saved_blocks(blocks:&[Block], palette:u64, leaf_count:usize) -> Result<Vec<usize>, LoadError> {
+    if blocks.is_empty() {
+        if BLOCKS.read().prefix_identity(leaf_count) != Some(palette) { return Err(LoadError::PaletteMismatch) }
+        return Ok((0 .. leaf_count).collect())
+    }
+    if blocks.len() != leaf_count { return Err(LoadError::Parse("Save's palette doesn't match its leaves".to_string())) }
+    Ok(blocks.iter().map(adopt_block).collect())
+}
+
+fn migrate(save:Value) -> Result<Value, LoadError> { run_migrations(save, &MIGRATIONS, 0) }
+
+fn migrate_world(save:Value) -> Result<Value, LoadError> { run_migrations(save, &WORLD_MIGRATIONS, 1) }
 
 impl EntityPool {
     pub fn save_entity(&self, id:ID) -> String {
         self.get_entity(id).unwrap().save()
     }
+
+    pub fn save_world(&self) -> String {
+        let graph = GRAPH.read();
+        let starts:Vec<ExternalPointer> = self.entities.iter().map(|entity| entity.location.pointer).collect();
+        let (roots, nodes) = graph.export_forest(&starts);
+        serde_json::to_string(&WorldStorer {
+            version: WORLD_VERSION,
+            leaf_count: graph.leaf_count(),
+            palette: BLOCKS.read().identity(),
+            blocks: BLOCKS.read().blocks().to_vec(),
+            entities: self.entities.iter().zip(roots).map(|(entity, root)| WorldEntity {
+                id: entity.id,
+                root,
+                min_cell_length: entity.location.min_cell_length,
+                position: entity.location.position,
+                rotation: entity.rotation,
+                velocity: entity.velocity,
+                angular_velocity: entity.angular_velocity,
+                body_type: entity.body_type,
+            }).collect(),
+            nodes: nodes.iter().map(|node| node.children()).collect(),
+        }).unwrap()
+    }
+
+    /// Replaces every entity with the ones in the world, leaving the pool untouched if the world is invalid
+    pub fn load_world(&mut self, data:&str) -> Result<(), LoadError> {
+        let storer: WorldStorer = serde_json::from_value(migrate_world(serde_json::from_str(data)?)?)?;
+        for (index, entity) in storer.entities.iter().enumerate() {
+            if storer.entities[.. index].iter().any(|other| other.id == entity.id) {
+                return Err(LoadError::Parse(format!("Entity {} is saved twice", entity.id)))
+            }
+        }
+        let table:Vec<_> = storer.nodes.iter().map(|children| Node::new(*children)).collect();
+        let found = table_leaves(&table);
+        if found != storer.leaf_count { return Err(LoadError::LeafCountMismatch { expected: storer.leaf_count, found }) }
+        let starts:Vec<ExternalPointer> = storer.entities.iter().map(|entity| entity.root).collect();
+        // Adopting blocks changes the palette and the graph, so nothing gets adopted from a world that won't load
+        for start in &starts { validate_table(&table, *start)? }
+        let blocks = saved_blocks(&storer.blocks, storer.palette, found)?;
+        let roots = {
+            let mut graph = GRAPH.write();
+            let roots = graph.import_forest(&starts, &table, &blocks)?;
+            for entity in &self.entities {
+                graph.release(entity.location.pointer.pointer);
+                for root in entity.history.roots() { graph.release(root.pointer) }
+            }
+            roots
+        };
+        self.entities = storer.entities.into_iter().zip(roots).map(|(saved, root)| {
+            let mut location = Location::new(saved.position, root);
+            location.min_cell_length = saved.min_cell_length;
+            Entity::from_saved(saved.id, location, saved.rotation, saved.velocity, saved.angular_velocity, saved.body_type)
+        }).collect();
+        Ok(())
+    }
     
 }
 
 impl Entity {
     pub fn save(&self) -> String {
+        let graph = GRAPH.read();
         serde_json::to_string_pretty(&EntityStorer {
+            version: SAVE_VERSION,
+            leaf_count: graph.leaf_count(),
+            palette: BLOCKS.read().identity(),
+            blocks: BLOCKS.read().blocks().to_vec(),
+            min_cell_length: self.location.min_cell_length,
             position: self.location.position,
             rotation: self.rotation,
             velocity: self.velocity,
             angular_velocity: self.angular_velocity,
-            graph: GRAPH.read().save_object_json(self.location.pointer),
+            body_type: self.body_type,
+            graph: graph.save_object_json(self.location.pointer),
         }).unwrap()
     }
-    pub fn load(data:String, id:ID) -> Entity {
-        let storer: EntityStorer = serde_json::from_str(&data).unwrap();
-        let pointer = GRAPH.write().load_object_json(storer.graph);
-        let location = Location::new(storer.position, pointer);
-        Entity {
-            id,
-            location,
-            rotation: storer.rotation,
-            forward: Vec2::from_angle(storer.rotation),
-            velocity: storer.velocity,
-            angular_velocity: storer.angular_velocity,
-            corners: corner_handling::tree_corners(location.pointer, location.min_cell_length),
-        }
+    pub fn load(data:String, id:ID) -> Result<Entity, LoadError> {
+        let storer: EntityStorer = serde_json::from_value(migrate(serde_json::from_str(&data)?)?)?;
+        let (root, table) = tree_from_json::<MetaNode>(&storer.graph, storer.leaf_count)?;
+        // Like worlds, nothing gets adopted from a save that won't load
+        let blocks = saved_blocks(&storer.blocks, storer.palette, storer.leaf_count)?;
+        let pointer = GRAPH.write().import_forest(&[root], &table, &blocks)?[0];
+        let mut location = Location::new(storer.position, pointer);
+        location.min_cell_length = storer.min_cell_length;
+        Ok(Self::from_saved(id, location, storer.rotation, storer.velocity, storer.angular_velocity, storer.body_type))
+    }
+
+    pub fn save_binary(&self) -> Vec<u8> {
+        let mut bytes = Vec::from(ENTITY_MAGIC);
+        bytes.push(ENTITY_VERSION);
+        bytes.push(BODY_TYPES.iter().position(|body_type| *body_type == self.body_type).unwrap() as u8);
+        for value in [
+            self.location.position.x,
+            self.location.position.y,
+            self.rotation,
+            self.velocity.x,
+            self.velocity.y,
+            self.angular_velocity,
+            self.location.min_cell_length.x,
+            self.location.min_cell_length.y,
+        ] { bytes.extend(value.to_le_bytes()) }
+        bytes.extend(GRAPH.read().save_object_binary(self.location.pointer));
+        bytes
+    }
+    pub fn load_binary(data:&[u8], id:ID) -> Result<Entity, LoadError> {
+        const FLOATS_START: usize = ENTITY_MAGIC.len() + 2;
+        const GRAPH_START: usize = FLOATS_START + 8 * 4;
+        if !data.starts_with(&ENTITY_MAGIC) { return Err(LoadError::Parse("Not a binary entity".to_string())) }
+        let truncated = || LoadError::Parse("Binary entity ended early".to_string());
+        let version = *data.get(ENTITY_MAGIC.len()).ok_or_else(truncated)?;
+        if version != ENTITY_VERSION { return Err(LoadError::UnsupportedVersion(version as u64)) }
+        if data.len() <= GRAPH_START { return Err(truncated()) }
+        let body_type = *BODY_TYPES.get(data[ENTITY_MAGIC.len() + 1] as usize)
+            .ok_or_else(|| LoadError::Parse(format!("Unknown body type {}", data[ENTITY_MAGIC.len() + 1])))?;
+        let floats:Vec<f32> = data[FLOATS_START .. GRAPH_START].chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        let pointer = GRAPH.write().load_object_binary(&data[GRAPH_START ..])?;
+        let mut location = Location::new(Vec2::new(floats[0], floats[1]), pointer);
+        location.min_cell_length = Vec2::new(floats[6], floats[7]);
+        Ok(Self::from_saved(id, location, floats[2], Vec2::new(floats[3], floats[4]), floats[5], body_type))
+    }
+
+    /// Picks the binary loader if the data starts with its magic, falling back to json otherwise
+    pub fn load_bytes(data:Vec<u8>, id:ID) -> Result<Entity, LoadError> {
+        if data.starts_with(&ENTITY_MAGIC) { Self::load_binary(&data, id) }
+        else { Self::load(String::from_utf8(data).map_err(|error| LoadError::Parse(error.to_string()))?, id) }
+    }
+
+    fn from_saved(id:ID, location:Location, rotation:f32, velocity:Vec2, angular_velocity:f32, body_type:BodyType) -> Entity {
+        Entity { velocity, angular_velocity, body_type, ..Entity::new(id, location, rotation) }
     }
 }
 
 #[derive(Serialize, Deserialize)]
 struct EntityStorer {
+    version: u32,
+    leaf_count: usize,
+    palette: u64,
+    blocks: Vec<Block>,
+    min_cell_length: Vec2,
     position: Vec2,
     rotation: f32,
     velocity: Vec2,
     angular_velocity: f32,
+    body_type: BodyType,
     graph: String
-}
\ No newline at end of file
+}
+
+// Every entity in the world shares the one node table
+#[derive(Serialize, Deserialize)]
+struct WorldStorer {
+    version: u32,
+    leaf_count: usize,
+    palette: u64,
+    blocks: Vec<Block>,
+    entities: Vec<WorldEntity>,
+    nodes: Vec<[Index; 4]>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WorldEntity {
+    id: ID,
+    root: ExternalPointer,
+    min_cell_length: Vec2,
+    position: Vec2,
+    rotation: f32,
+    velocity: Vec2,
+    angular_velocity: f32,
+    body_type: BodyType,
+}
+
+#[test]
+fn saves_migrate_to_body_types() {
+    let load = |bytes:&[u8]| -> EntityStorer { serde_json::from_value(migrate(serde_json::from_slice(bytes).unwrap()).unwrap()).unwrap() };
+    // The player predates versioning, the terrain was written as a version 2 save
+    assert_eq!(load(include_bytes!("../../../data/player.json")).body_type, BodyType::Dynamic);
+    assert_eq!(load(include_bytes!("../../../data/terrain.json")).body_type, BodyType::Static);
+}
+
+#[test]
+fn saves_need_a_matching_palette() {
+    let player = String::from_utf8(include_bytes!("../../../data/player.json").to_vec()).unwrap();
+    // Saves from before versioning were made with the four blocks the palette still starts with
+    let entity = Entity::load(player.clone(), 0).unwrap();
+    GRAPH.write().release(entity.location.pointer.pointer);
+    // The terrain was saved with the whole palette
+    let terrain = Entity::load(String::from_utf8(include_bytes!("../../../data/terrain.json").to_vec()).unwrap(), 0).unwrap();
+    GRAPH.write().release(terrain.location.pointer.pointer);
+    let mut other:Value = migrate(serde_json::from_str(&player).unwrap()).unwrap();
+    other["palette"] = (LEGACY_PALETTE ^ 1).into();
+    assert!(matches!(Entity::load(other.to_string(), 0), Err(LoadError::PaletteMismatch)));
+}
+
+#[test]
+fn worlds_round_trip() {
+    use macroquad::math::UVec2;
+    use crate::engine::grid::partition::ZorderPath;
+    let tree = |cells:&[UVec2]| {
+        let mut graph = GRAPH.write();
+        let blank = graph.get_root(0, 2).unwrap();
+        let grass = graph.leaf(1).unwrap();
+        let changes:Vec<_> = cells.iter().map(|cell| (ZorderPath::from_cell(*cell, 2), grass)).collect();
+        graph.set_nodes(blank, &changes).unwrap()
+    };
+    let mut pool = EntityPool::new();
+    pool.add_to_pool(Entity::new(3, Location::new(Vec2::new(1., 2.), tree(&[UVec2::new(0, 0), UVec2::new(3, 3)])), 0.5));
+    // Shares its top left quadrant with the first entity
+    let mut mover = Entity::new(7, Location::new(Vec2::new(-4., 0.), tree(&[UVec2::new(0, 0)])), 0.);
+    mover.velocity = Vec2::new(0.25, -0.5);
+    mover.angular_velocity = 0.125;
+    mover.body_type = BodyType::Static;
+    pool.add_to_pool(mover);
+    let saved = pool.save_world();
+    let mut loaded = EntityPool::new();
+    loaded.load_world(&saved).unwrap();
+    assert_eq!(loaded.save_world(), saved);
+    // A world naming the same entity twice is refused without touching the pool
+    assert!(loaded.load_world(&saved.replace("\"id\":7", "\"id\":3")).is_err());
+    // So is one whose table doesn't start with as many leaves as it says
+    let leaf_count = GRAPH.read().leaf_count();
+    let miscounted = saved.replace(&format!("\"leaf_count\":{leaf_count}"), &format!("\"leaf_count\":{}", leaf_count + 1));
+    assert!(matches!(loaded.load_world(&miscounted), Err(LoadError::LeafCountMismatch { .. })));
+    assert_eq!(loaded.save_world(), saved);
+    // A version 1 world has no palette or body types, so everything in it comes back as a mover
+    let mut old:Value = serde_json::from_str(&saved).unwrap();
+    old["version"] = 1.into();
+    old.as_object_mut().unwrap().remove("blocks");
+    for entity in old["entities"].as_array_mut().unwrap() { entity.as_object_mut().unwrap().remove("body_type"); }
+    loaded.load_world(&old.to_string()).unwrap();
+    assert!(loaded.entities.iter().all(|entity| entity.body_type == BodyType::Dynamic));
+    for entity in pool.entities.iter().chain(&loaded.entities) { GRAPH.write().release(entity.location.pointer.pointer) }
+}
+
+#[test]
+fn binary_entities_round_trip() {
+    let root = GRAPH.write().get_root(1, 2).unwrap();
+    let mut location = Location::new(Vec2::new(3., -1.), root);
+    location.min_cell_length = Vec2::new(2., 0.5);
+    let mut entity = Entity::new(4, location, 0.25);
+    entity.velocity = Vec2::new(-1., 0.5);
+    entity.angular_velocity = 2.;
+    entity.body_type = BodyType::Kinematic;
+    let saved = entity.save_binary();
+    let loaded = Entity::load_binary(&saved, 4).unwrap();
+    assert_eq!(loaded.body_type, BodyType::Kinematic);
+    assert_eq!(loaded.location.min_cell_length, Vec2::new(2., 0.5));
+    assert_eq!(loaded.save_binary(), saved);
+    let mut newer = saved.clone();
+    newer[ENTITY_MAGIC.len()] += 1;
+    assert!(matches!(Entity::load_binary(&newer, 4), Err(LoadError::UnsupportedVersion(_))));
+    for entity in [entity, loaded] { GRAPH.write().release(entity.location.pointer.pointer) }
+}
+
+#[test]
+fn entity_saves_remap_their_blocks() {
+    use macroquad::math::UVec2;
+    use crate::engine::grid::partition::ZorderPath;
+    let cell = ZorderPath::from_cell(UVec2::new(1, 0), 1);
+    let root = {
+        let mut graph = GRAPH.write();
+        let (blank, grass) = (graph.get_root(0, 1).unwrap(), graph.leaf(1).unwrap());
+        graph.set_nodes(blank, &[(cell, grass)]).unwrap()
+    };
+    let entity = Entity::new(0, Location::new(Vec2::ZERO, root), 0.);
+    let mut save:Value = serde_json::from_str(&entity.save()).unwrap();
+    // Swapping grass and stone in the saved palette swaps them in the loaded tree
+    let blocks = save["blocks"].as_array_mut().unwrap();
+    blocks.swap(1, 3);
+    let loaded = Entity::load(save.to_string(), 0).unwrap();
+    let block = {
+        let graph = GRAPH.read();
+        graph.block(graph.read(loaded.location.pointer, &cell.steps()).unwrap().pointer)
+    };
+    assert_eq!(block, Some(3));
+    for entity in [entity, loaded] { GRAPH.write().release(entity.location.pointer.pointer) }
+}
@@ -0,0 +1,139 @@
+use std::collections::{HashMap, VecDeque};
+use super::dag::{SparseDirectedGraph, GraphNode, ExternalPointer, Index, LoadError, MAX_TREE_HEIGHT, validate_table};
+
+// Layout: magic, version, then varints for leaf_count, root height, node count and root,
+// followed by four varint children per node. Leaves are numbered by block, nodes in bfs order after them.
+pub const MAGIC: [u8; 4] = *b"GDAG";
+pub const VERSION: u8 = 1;
+
+pub fn write_varint(bytes:&mut Vec<u8>, mut value:u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+pub fn read_varint(bytes:&[u8], cursor:&mut usize) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0 .. 64).step_by(7) {
+        let byte = *bytes.get(*cursor)?;
+        *cursor += 1;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 { return Some(value) }
+    }
+    None
+}
+
+fn truncated() -> LoadError { LoadError::Parse("Binary tree ended early".to_string()) }
+
+/// Decodes a binary tree into a node table laid out like TreeStorage (leaves first), without validating it.
+/// The header counts are checked before anything is allocated for them.
+fn decode_table<T: GraphNode>(bytes:&[u8], max_leaves:usize) -> Result<(ExternalPointer, Vec<T>), LoadError> {
+    if !bytes.starts_with(&MAGIC) { return Err(LoadError::Parse("Missing binary tree magic".to_string())) }
+    let version = *bytes.get(MAGIC.len()).ok_or_else(truncated)?;
+    if version != VERSION { return Err(LoadError::UnsupportedVersion(version as u64)) }
+    let mut cursor = MAGIC.len() + 1;
+    let next = |cursor:&mut usize| read_varint(bytes, cursor).ok_or_else(truncated);
+    let leaf_count = next(&mut cursor)? as usize;
+    // Binary trees carry no block names, so they can't use blocks the palette doesn't have
+    if leaf_count > max_leaves { return Err(LoadError::LeafCountMismatch { expected: max_leaves, found: leaf_count }) }
+    let height = next(&mut cursor)?;
+    let height = u32::try_from(height).map_err(|_| LoadError::HeightOverflow { height: u32::MAX, max: MAX_TREE_HEIGHT })?;
+    let node_count = next(&mut cursor)? as usize;
+    let root = next(&mut cursor)? as usize;
+    // Every child takes at least a byte
+    if node_count > (bytes.len() - cursor) / 4 { return Err(truncated()) }
+    let mut table:Vec<T> = (0 .. leaf_count).map(|leaf| T::new([Index(leaf); 4])).collect();
+    for _ in 0 .. node_count {
+        let mut children = [Index(0); 4];
+        for child in children.iter_mut() { *child = Index(next(&mut cursor)? as usize) }
+        table.push(T::new(children));
+    }
+    Ok((ExternalPointer::new(Index(root), height), table))
+}
+
+impl<T: GraphNode> SparseDirectedGraph<T> {
+    pub fn save_object_binary(&self, start:ExternalPointer) -> Vec<u8> {
+        let leaf_count = self.leaf_count();
+        let mut ids = HashMap::new();
+        let mut order = Vec::new();
+        let mut queue = VecDeque::from([start.pointer]);
+        while let Some(index) = queue.pop_front() {
+            if self.is_leaf(index) || ids.contains_key(&index) { continue }
+            ids.insert(index, leaf_count + order.len());
+            order.push(index);
+            queue.extend(self.node(index).unwrap().children());
+        }
+        let local = |index:Index| self.block(index).unwrap_or_else(|| ids[&index]);
+        let mut bytes = Vec::from(MAGIC);
+        bytes.push(VERSION);
+        write_varint(&mut bytes, leaf_count as u64);
+        write_varint(&mut bytes, start.height as u64);
+        write_varint(&mut bytes, order.len() as u64);
+        write_varint(&mut bytes, local(start.pointer) as u64);
+        for index in order {
+            for child in self.node(index).unwrap().children() {
+                write_varint(&mut bytes, local(child) as u64);
+            }
+        }
+        bytes
+    }
+
+    pub fn load_object_binary(&mut self, bytes:&[u8]) -> Result<ExternalPointer, LoadError> {
+        let (root, table) = decode_table::<T>(bytes, self.leaf_count())?;
+        validate_table(&table, root)?;
+        Ok(ExternalPointer::new(self.clone_graph(&table, root.pointer)?, root.height))
+    }
+}
+
+#[test]
+fn binary_round_trip() {
+    use super::dag::BasicNode;
+    use super::partition::ZorderPath;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let mut root = graph.get_root(0, 2).unwrap();
+    for (path, leaf) in [(vec![0, 3], 1), (vec![1], 3), (vec![3, 0], 2), (vec![3, 3], 1)] {
+        let path = path.into_iter().fold(ZorderPath::root(), |path, step| path.step_down(step));
+        root = graph.set_nodes(root, &[(path, Index(leaf))]).unwrap();
+    }
+    let bytes = graph.save_object_binary(root);
+    let mut other = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let loaded = other.load_object_binary(&bytes).unwrap();
+    assert_eq!(loaded.height, root.height);
+    assert_eq!(other.save_object_binary(loaded), bytes);
+}
+
+#[test]
+fn shared_nodes_can_point_back_in_bfs_order() {
+    use super::dag::BasicNode;
+    use super::partition::ZorderPath;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let root = graph.get_root(0, 3).unwrap();
+    // The same node is the root's first child and a grandchild through the second one,
+    // so the second child is numbered after the node it points at
+    let shared = ZorderPath::root().step_down(0).step_down(0);
+    let deeper = ZorderPath::root().step_down(1).step_down(0).step_down(0);
+    let root = graph.set_nodes(root, &[(shared, Index(1)), (deeper, Index(1))]).unwrap();
+    let first = graph.child(root.pointer, 0).unwrap();
+    assert_eq!(graph.child(graph.child(root.pointer, 1).unwrap(), 0).unwrap(), first);
+    let bytes = graph.save_object_binary(root);
+    let mut other = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let loaded = other.load_object_binary(&bytes).unwrap();
+    assert_eq!(other.save_object_binary(loaded), bytes);
+}
+
+#[test]
+fn corrupt_headers_fail_before_allocating() {
+    use super::dag::BasicNode;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let header = |leaf_count:u64, node_count:u64| {
+        let mut bytes = Vec::from(MAGIC);
+        bytes.push(VERSION);
+        for value in [leaf_count, 1, node_count, 4] { write_varint(&mut bytes, value) }
+        bytes
+    };
+    assert!(matches!(graph.load_object_binary(&header(u64::MAX >> 1, 1)), Err(LoadError::LeafCountMismatch { expected: 4, .. })));
+    assert!(matches!(graph.load_object_binary(&header(4, u64::MAX >> 1)), Err(LoadError::Parse(_))));
+    assert_eq!(graph.leaf_count(), 4);
+}
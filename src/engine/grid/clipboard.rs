@@ -0,0 +1,69 @@
+use vec_mem_heap::prelude::AccessError;
+use super::dag::{SparseDirectedGraph, GraphNode, ExternalPointer};
+use super::csg::CsgOp;
+use super::partition::ZorderPath;
+use super::transform::Transform;
+
+/// A copied subtree kept in its own node table, so it outlives compaction and world loads without holding references
+#[derive(Debug, Clone)]
+pub struct Clipboard<T> {
+    pub root: ExternalPointer,
+    nodes: Vec<T>,
+}
+
+impl<T: GraphNode> SparseDirectedGraph<T> {
+    pub fn copy_subtree(&self, start:ExternalPointer, path:ZorderPath) -> Option<Clipboard<T>> {
+        if path.depth > start.height { return None }
+        // A leaf above the path still covers the whole quadrant
+        let pointer = self.read(start, &path.steps())?;
+        let (roots, nodes) = self.export_forest(&[ExternalPointer::new(pointer.pointer, start.height - path.depth)]);
+        Some(Clipboard { root: roots[0], nodes })
+    }
+
+    /// Overwrites the quadrant at path with the clipboard, which has to be the same height as the quadrant
+    pub fn paste_subtree(&mut self, start:ExternalPointer, path:ZorderPath, clipboard:&Clipboard<T>) -> Result<ExternalPointer, AccessError> {
+        if path.depth > start.height || start.height - path.depth != clipboard.root.height { return Err(AccessError::OperationFailed) }
+        let pasted = self.clone_graph(&clipboard.nodes, clipboard.root.pointer).map_err(|_| AccessError::OperationFailed)?;
+        let result = self.set_nodes(start, &[(path, pasted)]);
+        // set_nodes took its own references, the ones from cloning were only keeping pasted alive
+        self.release(pasted);
+        result
+    }
+
+    /// Combines the clipboard with the quadrant at path instead of overwriting it
+    pub fn stamp_subtree(&mut self, start:ExternalPointer, path:ZorderPath, clipboard:&Clipboard<T>, op:CsgOp, is_solid:impl Fn(usize) -> bool) -> Result<ExternalPointer, AccessError> {
+        if path.depth > start.height || start.height - path.depth != clipboard.root.height { return Err(AccessError::OperationFailed) }
+        let under = self.read(start, &path.steps()).ok_or(AccessError::OperationFailed)?;
+        let pasted = self.clone_graph(&clipboard.nodes, clipboard.root.pointer).map_err(|_| AccessError::OperationFailed)?;
+        let combined = self.combine(ExternalPointer::new(under.pointer, clipboard.root.height), ExternalPointer::new(pasted, clipboard.root.height), op, is_solid)?;
+        let result = self.set_nodes(start, &[(path, combined.pointer)]);
+        self.release(pasted);
+        self.release(combined.pointer);
+        result
+    }
+
+    /// Transforms the clipboard in a scratch graph, leaving this one untouched
+    pub fn transform_clipboard(&self, clipboard:&Clipboard<T>, transform:Transform) -> Clipboard<T> {
+        let mut scratch = self.with_same_leaves();
+        let root = scratch.clone_graph(&clipboard.nodes, clipboard.root.pointer).unwrap();
+        let transformed = scratch.transform(ExternalPointer::new(root, clipboard.root.height), transform);
+        let (roots, nodes) = scratch.export_forest(&[transformed]);
+        Clipboard { root: roots[0], nodes }
+    }
+}
+
+#[test]
+fn paste_reproduces_copy() {
+    use macroquad::math::UVec2;
+    use super::dag::{BasicNode, Index};
+    use super::region::RegionEdit;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let mut source = graph.get_root(0, 3).unwrap();
+    source = graph.edit_rect(source, UVec2::new(0, 1), UVec2::new(2, 2), 0, RegionEdit::Fill(Index(2))).unwrap();
+    let clipboard = graph.copy_subtree(source, ZorderPath::root().step_down(0)).unwrap();
+    let mut target = graph.get_root(1, 3).unwrap();
+    target = graph.paste_subtree(target, ZorderPath::root().step_down(3), &clipboard).unwrap();
+    let (copied, pasted) = (graph.read(source, &[0]).unwrap(), graph.read(target, &[3]).unwrap());
+    assert_eq!((copied.pointer, copied.height), (pasted.pointer, pasted.height));
+    assert!(graph.validate(&[source, target]).is_valid());
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use vec_mem_heap::prelude::AccessError;
+use super::dag::{SparseDirectedGraph, GraphNode, ExternalPointer, Index};
+use crate::engine::blocks::BLANK_BLOCK;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    // Carves b's solid cells out of a
+    Difference,
+    Xor,
+    // Solid beats air, otherwise the higher block wins
+    Merge,
+}
+impl CsgOp {
+    // Cells an operation empties become the blank block
+    fn leaf(self, a:usize, b:usize, is_solid:&impl Fn(usize) -> bool) -> usize {
+        let (solid_a, solid_b) = (is_solid(a), is_solid(b));
+        match self {
+            Self::Union => if !solid_a && solid_b { b } else { a },
+            Self::Intersection => if solid_a && solid_b { a } else { BLANK_BLOCK },
+            Self::Difference => if solid_b { BLANK_BLOCK } else { a },
+            Self::Xor => match (solid_a, solid_b) {
+                (true, true) => BLANK_BLOCK,
+                (false, true) => b,
+                _ => a,
+            },
+            Self::Merge => if solid_a == solid_b { a.max(b) } else if solid_a { a } else { b },
+        }
+    }
+}
+
+impl<T: GraphNode> SparseDirectedGraph<T> {
+    /// Combines two trees of equal height cell by cell, is_solid being asked about blocks.
+    /// The result holds its own references and neither input is touched.
+    pub fn combine(&mut self, a:ExternalPointer, b:ExternalPointer, op:CsgOp, is_solid:impl Fn(usize) -> bool) -> Result<ExternalPointer, AccessError> {
+        if a.height != b.height { return Err(AccessError::OperationFailed) }
+        let mut memo = HashMap::new();
+        let root = self.combine_nodes(a.pointer, b.pointer, op, &is_solid, &mut memo);
+        self.retain(root);
+        Ok(ExternalPointer::new(root, a.height))
+    }
+
+    fn combine_nodes(&mut self, a:Index, b:Index, op:CsgOp, is_solid:&impl Fn(usize) -> bool, memo:&mut HashMap<(Index, Index), Index>) -> Index {
+        if let (Some(block_a), Some(block_b)) = (self.block(a), self.block(b)) { return self.leaf(op.leaf(block_a, block_b, is_solid)).unwrap() }
+        if let Some(combined) = memo.get(&(a, b)) { return *combined }
+        let (a_kids, b_kids) = (self.node(a).unwrap().children(), self.node(b).unwrap().children());
+        let mut children = [a; 4];
+        for slot in 0 .. 4 { children[slot] = self.combine_nodes(a_kids[slot], b_kids[slot], op, is_solid, memo) }
+        // Four matching leaves hash to that leaf, so results collapse on their own
+        let combined = self.find_or_add(T::new(children));
+        memo.insert((a, b), combined);
+        combined
+    }
+}
+
+#[test]
+fn csg_matches_cellwise() {
+    use macroquad::math::UVec2;
+    use super::dag::BasicNode;
+    use super::partition::ZorderPath;
+    use super::region::RegionEdit;
+    let is_solid = |block:usize| block % 2 == 1;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let mut a = graph.get_root(0, 3).unwrap();
+    a = graph.edit_rect(a, UVec2::new(0, 0), UVec2::new(4, 5), 0, RegionEdit::Fill(Index(1))).unwrap();
+    let mut b = graph.get_root(2, 3).unwrap();
+    b = graph.edit_rect(b, UVec2::new(3, 2), UVec2::new(7, 7), 0, RegionEdit::Fill(Index(3))).unwrap();
+    let mut roots = vec![a, b];
+    for op in [CsgOp::Union, CsgOp::Intersection, CsgOp::Difference, CsgOp::Xor, CsgOp::Merge] {
+        let combined = graph.combine(a, b, op, is_solid).unwrap();
+        for x in 0 .. 8 {
+            for y in 0 .. 8 {
+                let steps = ZorderPath::from_cell(UVec2::new(x, y), 3).steps();
+                let block = |root:ExternalPointer| graph.block(graph.read(root, &steps).unwrap().pointer).unwrap();
+                assert_eq!(block(combined), op.leaf(block(a), block(b), &is_solid));
+            }
+        }
+        roots.push(combined);
+    }
+    assert!(graph.validate(&roots).is_valid());
+}
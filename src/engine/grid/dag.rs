@@ -1,11 +1,19 @@
 use std::collections::{HashMap, VecDeque};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use vec_mem_heap::prelude::{NodeField, AccessError};
+use super::partition::ZorderPath;
 pub use vec_mem_heap::Index;
 
-pub trait GraphNode : Node + std::fmt::Debug + Clone + std::hash::Hash + Eq {}
+pub trait GraphNode : Node + std::fmt::Debug + Clone + std::hash::Hash + Eq {
+    /// What a leaf needs to know about its block, handed in by whoever registers the block
+    type LeafPayload : Copy + Default + std::fmt::Debug;
+    fn new_leaf(index:Index, _block:usize, _payload:Self::LeafPayload) -> Self { Self::new([index; 4]) }
+    /// Recomputes any cached payload from the children, which are already up to date.
+    /// Hash and Eq must only look at children so stale payloads still find their node.
+    fn refresh(&mut self, _children:[&Self; 4]) {}
+}
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize, derive_new::new)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, derive_new::new)]
 pub struct ExternalPointer {
     pub pointer : Index,
     pub height : u32
@@ -14,9 +22,10 @@ pub struct ExternalPointer {
 pub trait Node : Clone {
     fn new(children:[Index; 4]) -> Self;
     fn children(&self) -> [Index; 4];
-    fn set_child(&mut self, child:usize, index:Index);
 }
 
+// Payload free, the game itself uses MetaNode
+#[allow(dead_code)]
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct BasicNode {
     children : [Index; 4],
@@ -24,9 +33,10 @@ pub struct BasicNode {
 impl Node for BasicNode {
     fn new(children:[Index; 4]) -> Self { Self { children } }
     fn children(&self) -> [Index; 4] { self.children }
-    fn set_child(&mut self, child:usize, index:Index) { self.children[child] = index }
 }
-impl GraphNode for BasicNode {}
+impl GraphNode for BasicNode {
+    type LeafPayload = ();
+}
 impl<T> Node for vec_mem_heap::internals::MemorySlot<T> where T: GraphNode {
     fn new(_:[Index; 4]) -> Self { panic!("Don't do that!") }
     fn children(&self) -> [Index; 4] {
@@ -35,34 +45,94 @@ impl<T> Node for vec_mem_heap::internals::MemorySlot<T> where T: GraphNode {
             Err(_) => { panic!("Attempted use after free") }
         }
     }
-    fn set_child(&mut self, child:usize, index:Index) {
-        match self.unwrap_steward_mut() {
-            Ok(steward) => { steward.data.set_child(child, index) }
-            Err(err) => { panic!("{err:?}") }
-        }
-    }
 }
+/// Leaves are the only nodes that are their own four children, so walks can step into a leaf and keep getting it back.
+/// That's how a leaf lines up with a subtree it's compared or combined with, splitting as far as the subtree goes.
 pub struct SparseDirectedGraph<T: GraphNode> {
     pub nodes : NodeField<T>,
     pub index_lookup : HashMap<T, Index>,
-    leaf_count : u8, 
+    // Block n is the leaf at leaves[n], blocks registered later can sit anywhere in the node field
+    leaves : Vec<Index>,
+    leaf_blocks : HashMap<Index, usize>,
+    leaf_payloads : Vec<T::LeafPayload>,
 }
 impl<T: GraphNode> SparseDirectedGraph<T> {
     //Utility
-    pub fn new(leaf_count:u8) -> Self {
+    /// A graph with a leaf for each payload, block n getting payloads[n]
+    pub fn with_leaves(payloads:&[T::LeafPayload]) -> Self {
         let mut instance = Self {
             nodes : NodeField::new(),
             index_lookup : HashMap::new(),
-            leaf_count
+            leaves : Vec::new(),
+            leaf_blocks : HashMap::new(),
+            leaf_payloads : Vec::new(),
         };
-        for i in 0 .. leaf_count {
-            instance.add_node(T::new([Index(i as usize); 4]));
-        }
+        for payload in payloads { instance.add_leaf(*payload); }
         instance
     }
     
+    pub fn leaf_count(&self) -> usize { self.leaves.len() }
+
+    /// An empty graph with the same leaves, for scratch work that gets copied back in
+    pub(super) fn with_same_leaves(&self) -> Self { Self::with_leaves(&self.leaf_payloads) }
+
     pub fn is_leaf(&self, index:Index) -> bool {
-        *index < self.leaf_count as usize
+        self.leaf_blocks.contains_key(&index)
+    }
+
+    /// The leaf standing for block, None for blocks the graph hasn't registered
+    pub fn leaf(&self, block:usize) -> Option<Index> { self.leaves.get(block).copied() }
+
+    fn leaf_pairs(&self) -> Vec<(Index, usize)> {
+        self.leaves.iter().copied().zip(0 ..).collect()
+    }
+
+    /// The block a leaf stands for, None for anything that isn't a leaf
+    pub fn block(&self, index:Index) -> Option<usize> { self.leaf_blocks.get(&index).copied() }
+
+    /// Registers the next block, which can happen at any point since leaves don't need to be contiguous
+    pub fn add_leaf(&mut self, payload:T::LeafPayload) -> usize {
+        let block = self.leaves.len();
+        // A leaf is its own child, so its slot has to exist before the node can be built
+        let index = self.nodes.push(T::new([Index(0); 4]));
+        let leaf = T::new_leaf(index, block, payload);
+        self.nodes.replace(index, leaf.clone()).unwrap();
+        self.index_lookup.insert(leaf, index);
+        self.leaves.push(index);
+        self.leaf_blocks.insert(index, block);
+        self.leaf_payloads.push(payload);
+        block
+    }
+
+    /// Gives block's leaf a new payload, refreshing everything built on top of it
+    pub fn set_leaf_payload(&mut self, block:usize, payload:T::LeafPayload) {
+        let index = self.leaves[block];
+        let leaf = T::new_leaf(index, block, payload);
+        // Nodes hash and compare on their children, so index_lookup doesn't need to hear about it
+        self.nodes.replace(index, leaf).unwrap();
+        self.leaf_payloads[block] = payload;
+        self.refresh_all();
+    }
+
+    // Recomputes what every node caches, children first
+    fn refresh_all(&mut self) {
+        let slots = self.nodes.internal_memory().len();
+        let mut done = vec![false; slots];
+        for slot in 0 .. slots {
+            let mut stack = vec![(Index(slot), false)];
+            while let Some((index, finished)) = stack.pop() {
+                if done[*index] || self.is_leaf(index) { continue }
+                let Ok(node) = self.node(index).cloned() else { continue };
+                if finished {
+                    let node = self.refreshed(node);
+                    self.nodes.replace(index, node).unwrap();
+                    done[*index] = true;
+                } else {
+                    stack.push((index, true));
+                    stack.extend(node.children().into_iter().filter(|child| !done[**child]).map(|child| (child, false)));
+                }
+            }
+        }
     }
 
     fn get_trail(&self, start:Index, path:&[u32]) -> Vec<Index>  {
@@ -83,66 +153,182 @@ impl<T: GraphNode> SparseDirectedGraph<T> {
         self.index_lookup.get(node).copied()
     }
 
-    fn add_node(&mut self, node:T) -> Index {
+    fn insert_node(&mut self, node:T) -> Index {
         let index = self.nodes.push(node.clone());
         self.index_lookup.insert(node, index);
         index
     }
 
-    fn propagate_change(
-        &mut self,
-        path: &[u32],
-        trail: &[Index],
-        mut cur_pointer: ExternalPointer,
-        mut old_parent: Index,
-    ) -> Result<(Index, ExternalPointer, Option<T>), AccessError> {
-        for cur_depth in (0 .. path.len()).rev() {
-            //Trailing off early means we're at a leaf, we can just repeat that leaf and get sparsity by default
-            old_parent = if cur_depth < trail.len() { trail[cur_depth] } else { *trail.last().unwrap() };
-            let new_parent_node =  {
-                let mut new_parent = self.node(old_parent)?.clone();
-                new_parent.set_child(path[cur_depth] as usize, cur_pointer.pointer);
-                new_parent
-            };
-            cur_pointer.height += 1;
-            cur_pointer.pointer = match self.find_index(&new_parent_node) {
-                Some(pointer) => pointer,
-                None => {
-                    if self.nodes.status(old_parent).unwrap().get() == 2 && !self.is_leaf(old_parent) {
-                        cur_pointer.pointer = old_parent;
-                        return Ok((old_parent, cur_pointer, Some(new_parent_node)))
-                    } else { self.add_node(new_parent_node) }
-                }
-            };
-        };
-        Ok((old_parent, cur_pointer, None))
+    fn add_node(&mut self, node:T) -> Index {
+        let node = self.refreshed(node);
+        self.insert_node(node)
+    }
+
+    fn refreshed(&self, mut node:T) -> T {
+        let children = node.children().map(|child| self.node(child).unwrap());
+        node.refresh(children);
+        node
+    }
+
+    // Hash conses a freshly built node, new nodes only hold their base reference
+    pub(super) fn find_or_add(&mut self, node:T) -> Index {
+        match self.find_index(&node) {
+            Some(index) => index,
+            None => self.add_node(node),
+        }
     }
 
     //Public functions used for writing
-    pub fn set_node(&mut self, start:ExternalPointer, path:&[u32], new_pointer:Index) -> Result<ExternalPointer, AccessError> {
-        if let Some(pointer) = self.read(start, path) { 
-            if pointer.pointer == new_pointer { return Ok(start) }
-        } else { return Err(AccessError::OperationFailed) }
-        let trail = self.get_trail(start.pointer, path);
-        let (old_parent, cur_pointer, early_node) = self.propagate_change(
-            path,
-            &trail[..],
-            ExternalPointer::new(new_pointer, start.height - path.len() as u32),
-            start.pointer,
-        )?;
-        let last_leaf = self.leaf_count as usize - 1;
-        let old_nodes = bfs_nodes(self.nodes.internal_memory(), old_parent, last_leaf); 
-        let early_exit = match early_node { Some(node) => {
-            self.index_lookup.remove(&self.nodes.replace(old_parent, node.clone()).unwrap());
-            self.index_lookup.insert(node, old_parent);
-            true
-        } None => { false }};
-        for index in bfs_nodes(self.nodes.internal_memory(), cur_pointer.pointer, last_leaf) {
-            self.nodes.add_ref(index).unwrap()
+    /// Applies every change in a single bottom-up rebuild, later changes win where they overlap.
+    /// Only references along the changed paths are adjusted, subtrees shared with the old root are skipped.
+    pub fn set_nodes(&mut self, start:ExternalPointer, changes:&[(ZorderPath, Index)]) -> Result<ExternalPointer, AccessError> {
+        if changes.iter().any(|(path, _)| path.depth > start.height) { return Err(AccessError::OperationFailed) }
+        let changes:Vec<&(ZorderPath, Index)> = changes.iter().collect();
+        let new_root = self.rebuild(start.pointer, 0, &changes)?;
+        let mut removed = Vec::new();
+        self.swap_refs(start.pointer, new_root, &mut removed);
+        self.mass_remove(&removed);
+        Ok(ExternalPointer::new(new_root, start.height))
+    }
+
+    /// Puts start in quadrant of a new root one level taller, filling the other quadrants with fill.
+    /// Start's references move into the new root.
+    pub fn wrap_root(&mut self, start:ExternalPointer, quadrant:u32, fill:Index) -> Result<ExternalPointer, AccessError> {
+        if start.height >= MAX_TREE_HEIGHT || quadrant > 3 { return Err(AccessError::OperationFailed) }
+        let mut children = [fill; 4];
+        children[quadrant as usize] = start.pointer;
+        let root = self.find_or_add(T::new(children));
+        // Wrapping a leaf in itself collapses back into that leaf, which already holds the references it needs
+        if root != start.pointer {
+            self.nodes.add_ref(root).unwrap();
+            for _ in 0 .. 3 { self.retain(fill) }
+        }
+        Ok(ExternalPointer::new(root, start.height + 1))
+    }
+
+    /// Keeps only the child in quadrant as the new root, releasing everything else start held
+    pub fn crop_root(&mut self, start:ExternalPointer, quadrant:u32) -> Result<ExternalPointer, AccessError> {
+        if start.height == 0 || quadrant > 3 { return Err(AccessError::OperationFailed) }
+        let child = self.child(start.pointer, quadrant as usize)?;
+        if !self.is_leaf(start.pointer) {
+            let mut removed = vec![start.pointer];
+            for (slot, kid) in self.node(start.pointer)?.children().into_iter().enumerate() {
+                if slot != quadrant as usize { removed.extend(bfs_nodes(self.nodes.internal_memory(), kid)) }
+            }
+            self.mass_remove(&removed);
+        }
+        Ok(ExternalPointer::new(child, start.height - 1))
+    }
+
+    /// The only quadrant of the root holding anything but blank, if there is just one
+    pub fn trim_quadrant(&self, start:ExternalPointer, blank:Index) -> Option<u32> {
+        if start.height == 0 || self.is_leaf(start.pointer) { return None }
+        let children = self.node(start.pointer).ok()?.children();
+        let mut filled = (0 .. 4).filter(|slot| children[*slot] != blank);
+        let quadrant = filled.next()?;
+        if filled.next().is_some() { return None }
+        Some(quadrant as u32)
+    }
+
+    // The children of the half sized window centered on a node with these children, when everything outside it is blank.
+    // The children have to be at least one level above the cells.
+    fn centered_window(&self, children:[Index; 4], blank:Index) -> Option<[Index; 4]> {
+        let mut inner = [blank; 4];
+        for (slot, child) in children.into_iter().enumerate() {
+            let grandchildren = self.node(child).ok()?.children();
+            // The grandchild touching the center sits in the opposite slot
+            for (grand_slot, grandchild) in grandchildren.into_iter().enumerate() {
+                if grand_slot == slot ^ 0b11 { inner[slot] = grandchild }
+                else if grandchild != blank { return None }
+            }
+        }
+        Some(inner)
+    }
+
+    /// Whether everything but blank sits in the half sized window centered on the root
+    pub fn is_centered(&self, start:ExternalPointer, blank:Index) -> bool {
+        if start.pointer == blank { return true }
+        if start.height < 2 { return false }
+        self.node(start.pointer).is_ok_and(|node| self.centered_window(node.children(), blank).is_some())
+    }
+
+    /// Re-roots onto the half sized window centered on the root when everything outside it is blank.
+    /// The center of the grid doesn't move, start's references are swapped for the new root's.
+    pub fn recenter_root(&mut self, start:ExternalPointer, blank:Index) -> Option<ExternalPointer> {
+        if start.height < 2 || self.is_leaf(start.pointer) { return None }
+        let inner = self.centered_window(self.node(start.pointer).ok()?.children(), blank)?;
+        let root = self.find_or_add(T::new(inner));
+        self.retain(root);
+        self.release(start.pointer);
+        Some(ExternalPointer::new(root, start.height - 1))
+    }
+
+    /// Re-roots onto a tree one level taller around the same center, each quadrant moving out to touch the center.
+    /// Undoes recenter_root, start's references are swapped for the new root's.
+    pub fn expand_root(&mut self, start:ExternalPointer, blank:Index) -> Result<ExternalPointer, AccessError> {
+        if start.height == 0 || start.height >= MAX_TREE_HEIGHT { return Err(AccessError::OperationFailed) }
+        let children = self.node(start.pointer)?.children();
+        let mut outer = [blank; 4];
+        for (slot, child) in children.into_iter().enumerate() {
+            let mut inner = [blank; 4];
+            inner[slot ^ 0b11] = child;
+            outer[slot] = self.find_or_add(T::new(inner));
+        }
+        let root = self.find_or_add(T::new(outer));
+        self.retain(root);
+        self.release(start.pointer);
+        Ok(ExternalPointer::new(root, start.height + 1))
+    }
+
+    /// One step toward keeping everything but blank inside the centered window with room to spare.
+    /// Grows when something reaches outside the window, shrinks when the smaller tree would still pass that test,
+    /// and returns None once neither applies. The center of the grid never moves.
+    pub fn fit_root(&mut self, start:ExternalPointer, blank:Index) -> Option<ExternalPointer> {
+        if !self.is_centered(start, blank) { return self.expand_root(start, blank).ok() }
+        if start.height < 3 || self.is_leaf(start.pointer) { return None }
+        let inner = self.centered_window(self.node(start.pointer).ok()?.children(), blank)?;
+        // Shrinking any further would only grow straight back on the next step
+        self.centered_window(inner, blank)?;
+        self.recenter_root(start, blank)
+    }
+
+    fn rebuild(&mut self, node:Index, depth:u32, changes:&[&(ZorderPath, Index)]) -> Result<Index, AccessError> {
+        // The last change covering this whole node overwrites it, so only finer changes after that still apply
+        let (base, remaining) = match changes.iter().rposition(|(path, _)| path.depth == depth) {
+            Some(last) => (changes[last].1, &changes[last + 1 ..]),
+            None => (node, changes),
+        };
+        if remaining.is_empty() { return Ok(base) }
+        let mut children = self.node(base)?.children();
+        for (step, child) in children.iter_mut().enumerate() {
+            let below:Vec<_> = remaining.iter().copied().filter(|(path, _)| path.read_step(depth + 1) == step as u32).collect();
+            if !below.is_empty() { *child = self.rebuild(*child, depth + 1, &below)? }
+        }
+        let new_node = T::new(children);
+        Ok(match self.find_index(&new_node) {
+            Some(index) => index,
+            None => self.add_node(new_node),
+        })
+    }
+
+    // Moves one occurrence worth of references from the old tree to the new one.
+    // Removals are only collected so nothing the new tree needs gets freed early.
+    fn swap_refs(&mut self, old:Index, new:Index, removed:&mut Vec<Index>) {
+        if old == new { return }
+        self.nodes.add_ref(new).unwrap();
+        removed.push(old);
+        match (self.is_leaf(old), self.is_leaf(new)) {
+            (false, false) => {
+                let old_kids = self.node(old).unwrap().children();
+                let new_kids = self.node(new).unwrap().children();
+                for (old_kid, new_kid) in old_kids.into_iter().zip(new_kids) { self.swap_refs(old_kid, new_kid, removed) }
+            }
+            (true, false) => for kid in self.node(new).unwrap().children() { self.retain(kid) },
+            (false, true) => for kid in self.node(old).unwrap().children() {
+                removed.extend(bfs_nodes(self.nodes.internal_memory(), kid))
+            },
+            (true, true) => {}
         }
-        self.mass_remove(&old_nodes);
-        // Returning start because the root node never changes
-        if early_exit { Ok(start) } else { Ok(cur_pointer) }
     }
 
     pub fn mass_remove(&mut self, indices:&[Index]) {
@@ -169,11 +355,191 @@ impl<T: GraphNode> SparseDirectedGraph<T> {
         Some(ExternalPointer::new(*node_pointer, start.height - (trail.len() as u32 - 1)))
     }
 
-    pub fn get_root(&mut self, leaf:usize, height:u32) -> ExternalPointer {
-        self.nodes.add_ref(Index(leaf)).unwrap();
-        ExternalPointer::new(Index(leaf), height)
+    pub fn get_root(&mut self, block:usize, height:u32) -> Option<ExternalPointer> {
+        let leaf = self.leaf(block)?;
+        self.nodes.add_ref(leaf).unwrap();
+        Some(ExternalPointer::new(leaf, height))
+    }
+
+    // Clippy thinks I should pass a slice here instead of a vector, but passing a partial slice is very likely to lead to operation failure
+    /// Copies a tree out of a foreign table (leaves first, by block), registering any blocks the graph doesn't have yet.
+    /// Foreign tables should go through validate_table first
+    pub fn clone_graph<N : Node> (&mut self, from:&Vec<N>, start:Index) -> Result<Index, LoadError> {
+        let blocks:Vec<usize> = (0 .. table_leaves(from)).collect();
+        self.clone_remapped(from, start, &blocks)
+    }
+
+    /// clone_graph for tables whose leaf n stands for blocks[n] in this graph
+    pub fn clone_remapped<N : Node> (&mut self, from:&Vec<N>, start:Index, blocks:&[usize]) -> Result<Index, LoadError> {
+        let leaves:Vec<(Index, usize)> = blocks.iter().enumerate().map(|(leaf, block)| (Index(leaf), *block)).collect();
+        self.clone_from(from, start, &leaves)
     }
 
+    // Each leaf of from is paired with the block it stands for
+    fn clone_from<N : Node> (&mut self, from:&Vec<N>, start:Index, leaves:&[(Index, usize)]) -> Result<Index, LoadError> {
+        let mut remapped = HashMap::new();
+        for (leaf, block) in leaves {
+            // Nothing is known about blocks this graph hasn't seen yet
+            while self.leaf_count() <= *block { self.add_leaf(T::LeafPayload::default()); }
+            remapped.insert(*leaf, self.leaf(*block).unwrap());
+        }
+        for pointer in bfs_nodes(from, start).into_iter().rev() {
+            if !remapped.contains_key(&pointer) {
+                let old_kids = from[*pointer].children();
+                let mut new_kids = [Index(0); 4];
+                for (new_kid, old_kid) in new_kids.iter_mut().zip(old_kids) {
+                    *new_kid = *remapped.get(&old_kid).ok_or(LoadError::DanglingChild { node: *pointer, child: *old_kid })?;
+                }
+                let new_node = T::new(new_kids);
+                let new_index = match self.find_index(&new_node) {
+                    Some(index) => index,
+                    None => self.add_node(new_node),
+                };
+                remapped.insert(pointer, new_index);
+            }
+            self.nodes.add_ref(remapped[&pointer]).unwrap();
+        }
+        Ok(remapped[&start])
+    }
+
+    /// Adds the references a root needs on its whole tree
+    pub fn retain(&mut self, root:Index) {
+        for index in bfs_nodes(self.nodes.internal_memory(), root) {
+            self.nodes.add_ref(index).unwrap()
+        }
+    }
+
+    /// Drops the references a root holds on its whole tree
+    pub fn release(&mut self, root:Index) {
+        let nodes = bfs_nodes(self.nodes.internal_memory(), root);
+        self.mass_remove(&nodes);
+    }
+
+    /// Rebuilds the node field with only what's reachable from roots, packed densely with children before parents.
+    /// The returned roots line up with the ones passed in and hold the same references; every other root is invalidated.
+    pub fn compact(&mut self, roots:&[ExternalPointer]) -> Compaction {
+        let leaves = self.leaf_pairs();
+        let mut compacted = self.with_same_leaves();
+        let new_roots = roots.iter().map(|root| ExternalPointer::new(
+            compacted.clone_from(self.nodes.internal_memory(), root.pointer, &leaves).unwrap(),
+            root.height
+        )).collect();
+        let old_slots = self.nodes.internal_memory().len();
+        let old_live = self.live_nodes();
+        *self = compacted;
+        Compaction {
+            roots: new_roots,
+            reclaimed_slots: old_slots.saturating_sub(self.nodes.internal_memory().len()),
+            dropped_nodes: old_live.saturating_sub(self.live_nodes()),
+        }
+    }
+
+    pub fn live_nodes(&self) -> usize {
+        (0 .. self.nodes.internal_memory().len()).filter(|index| self.nodes.data(Index(*index)).is_ok()).count()
+    }
+
+    /// Copies several roots into a single node table, so subtrees they share are only stored once.
+    pub fn export_forest(&self, starts:&[ExternalPointer]) -> (Vec<ExternalPointer>, Vec<T>) {
+        // A fresh graph packs its leaves at the front in block order, which is the table layout
+        let leaves = self.leaf_pairs();
+        let mut object_graph = self.with_same_leaves();
+        let roots = starts.iter().map(|start| ExternalPointer::new(
+            object_graph.clone_from(self.nodes.internal_memory(), start.pointer, &leaves).unwrap(),
+            start.height
+        )).collect();
+        (roots, object_graph.nodes.internal_memory().iter().map(|node| T::new(node.children())).collect())
+    }
+
+    /// Validates every root against the table before cloning any of them in, table leaf n standing for blocks[n]
+    pub fn import_forest(&mut self, roots:&[ExternalPointer], table:&Vec<T>, blocks:&[usize]) -> Result<Vec<ExternalPointer>, LoadError> {
+        for root in roots { validate_table(table, *root)? }
+        if blocks.len() != table_leaves(table) { return Err(LoadError::Parse("Block mapping doesn't match the table's leaves".to_string())) }
+        roots.iter().map(|root| Ok(ExternalPointer::new(self.clone_remapped(table, root.pointer, blocks)?, root.height))).collect()
+    }
+
+}
+
+#[derive(Debug)]
+pub struct Compaction {
+    pub roots: Vec<ExternalPointer>,
+    // Slots the node field no longer needs to allocate
+    pub reclaimed_slots: usize,
+    // Live nodes nothing could reach, usually leaked references
+    pub dropped_nodes: usize,
+}
+
+// ZorderPath packs two bits per layer into a u32
+pub const MAX_TREE_HEIGHT: u32 = 16;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Parse(String),
+    UnsupportedVersion(u64),
+    DanglingRoot(usize),
+    DanglingChild { node: usize, child: usize },
+    Cycle(usize),
+    LeafCountMismatch { expected: usize, found: usize },
+    PaletteMismatch,
+    HeightOverflow { height: u32, max: u32 },
+}
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(error) => write!(f, "Failed to parse save: {error}"),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported save version {version}"),
+            Self::DanglingRoot(root) => write!(f, "Root {root} is outside the node table"),
+            Self::DanglingChild { node, child } => write!(f, "Node {node} points at missing child {child}"),
+            Self::Cycle(node) => write!(f, "Node {node} is its own ancestor"),
+            Self::LeafCountMismatch { expected, found } => write!(f, "Expected {expected} leaves, found {found}"),
+            Self::PaletteMismatch => write!(f, "Save was made with a block palette this one doesn't start with"),
+            Self::HeightOverflow { height, max } => write!(f, "Tree height {height} exceeds {max}"),
+        }
+    }
+}
+impl From<serde_json::Error> for LoadError {
+    fn from(error: serde_json::Error) -> Self { Self::Parse(error.to_string()) }
+}
+
+/// How many leaves a foreign node table starts with, leaf n standing for block n
+pub fn table_leaves<N: Node>(table:&[N]) -> usize {
+    table.iter().enumerate().take_while(|(i, node)| node.children() == [Index(*i); 4]).count()
+}
+
+/// Checks a foreign node table (leaves first, as written by the savers) before anything from it touches a graph.
+pub fn validate_table<N: Node>(table:&[N], root:ExternalPointer) -> Result<(), LoadError> {
+    if root.height > MAX_TREE_HEIGHT { return Err(LoadError::HeightOverflow { height: root.height, max: MAX_TREE_HEIGHT }) }
+    let leaf_count = table_leaves(table);
+    if *root.pointer >= table.len() { return Err(LoadError::DanglingRoot(*root.pointer)) }
+    // Iterative dfs, 1 marks nodes on the current path and 2 marks finished nodes
+    let mut state = vec![0u8; table.len()];
+    let mut heights = vec![0u32; table.len()];
+    let mut stack = vec![(*root.pointer, false)];
+    while let Some((node, finished)) = stack.pop() {
+        if node < leaf_count { continue }
+        let children = table[node].children();
+        if finished {
+            heights[node] = 1 + children.iter().map(|child| heights[**child]).max().unwrap();
+            state[node] = 2;
+            continue
+        }
+        match state[node] {
+            1 => return Err(LoadError::Cycle(node)),
+            2 => continue,
+            _ => state[node] = 1,
+        }
+        stack.push((node, true));
+        for child in children {
+            if *child >= table.len() { return Err(LoadError::DanglingChild { node, child: *child }) }
+            match state[*child] {
+                1 => return Err(LoadError::Cycle(*child)),
+                0 => stack.push((*child, false)),
+                _ => {}
+            }
+        }
+    }
+    let height = heights[*root.pointer];
+    if height > root.height { return Err(LoadError::HeightOverflow { height, max: root.height }) }
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize)]
@@ -181,54 +547,191 @@ struct TreeStorage<T : GraphNode> {
     root: ExternalPointer,
     nodes: Vec<T>,
 }
-//Assumes constant leaf count. Eventually add more metadata
 impl<T: GraphNode + Serialize + DeserializeOwned> SparseDirectedGraph<T> {
     pub fn save_object_json(&self, start:ExternalPointer) -> String {
-        let mut object_graph = Self::new(self.leaf_count);
-        let root_index = object_graph.clone_graph(self.nodes.internal_memory(), start.pointer);
-        serde_json::to_string(&TreeStorage {
-            root : ExternalPointer::new(root_index, start.height),
-            nodes : object_graph.nodes.internal_memory().iter().map(|node| T::new(node.children())).collect(), 
-        }).unwrap()
-    }
-    
-    //Currently requires the nodetype of both graph and data to be the same.
-    pub fn load_object_json(&mut self, json:String) -> ExternalPointer {
-        let temp:TreeStorage<T> = serde_json::from_str(&json).unwrap();
-        ExternalPointer::new(self.clone_graph(&temp.nodes, temp.root.pointer), temp.root.height)
-    }
-
-    // Clippy thinks I should pass a slice here instead of a vector, but passing a partial slice is very likely to lead to operation failure
-    //Assumes equal leaf count (between the two graphs)
-    fn clone_graph<N : Node> (&mut self, from:&Vec<N>, start:Index) -> Index {
-        let mut remapped = HashMap::new();
-        for i in 0 .. self.leaf_count as usize { remapped.insert(Index(i), Index(i)); }
-        for pointer in bfs_nodes(from, start, self.leaf_count as usize - 1).into_iter().rev() {
-            if !remapped.contains_key(&pointer) {
-                let old_kids = &from[*pointer].children();
-                let new_node = T::new([
-                    *remapped.get(&old_kids[0]).unwrap(),
-                    *remapped.get(&old_kids[1]).unwrap(),
-                    *remapped.get(&old_kids[2]).unwrap(),
-                    *remapped.get(&old_kids[3]).unwrap()
-                ]);
-                remapped.insert(pointer, self.add_node(new_node));
-            }
-            self.nodes.add_ref(*remapped.get(&pointer).unwrap()).unwrap();
-        }
-        *remapped.get(&start).unwrap()
+        let (roots, nodes) = self.export_forest(&[start]);
+        serde_json::to_string(&TreeStorage { root : roots[0], nodes }).unwrap()
     }
+}
 
+//Currently requires the nodetype of both graph and data to be the same.
+/// Parses and validates a tree written by save_object_json, saved alongside the number of leaves its table was written with.
+/// Nothing touches a graph until the table goes through import_forest.
+pub fn tree_from_json<T: GraphNode + DeserializeOwned>(json:&str, leaf_count:usize) -> Result<(ExternalPointer, Vec<T>), LoadError> {
+    let temp:TreeStorage<T> = serde_json::from_str(json)?;
+    let found = table_leaves(&temp.nodes);
+    if found != leaf_count { return Err(LoadError::LeafCountMismatch { expected: leaf_count, found }) }
+    validate_table(&temp.nodes, temp.root)?;
+    Ok((temp.root, temp.nodes))
 }
 
-// Assumes leaves are stored contiguously at the front of the slice.
-pub fn bfs_nodes<N: Node>(nodes:&Vec<N>, start:Index, last_leaf:usize) -> Vec<Index> {
+// Every node under start, once for each path reaching it
+pub fn bfs_nodes<N: Node>(nodes:&Vec<N>, start:Index) -> Vec<Index> {
     let mut queue = VecDeque::from([start]);
     let mut bfs_indexes = Vec::new();
     while let Some(index) = queue.pop_front() {
         bfs_indexes.push(index);
-        if *index > last_leaf { queue.extend(nodes[*index].children()) }
+        let children = nodes[*index].children();
+        if children[0] != index { queue.extend(children) }
     }
     bfs_indexes
 }
 
+
+#[test]
+fn set_nodes_batches_match_single_changes() {
+    let mut batched = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let mut sequential = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let mut batched_root = batched.get_root(0, 3).unwrap();
+    let mut sequential_root = sequential.get_root(0, 3).unwrap();
+    let changes = [
+        (ZorderPath::from_cell(macroquad::math::UVec2::new(1, 2), 3), Index(1)),
+        (ZorderPath::from_cell(macroquad::math::UVec2::new(0, 0), 1), Index(3)),
+        (ZorderPath::from_cell(macroquad::math::UVec2::new(0, 1), 3), Index(2)),
+        (ZorderPath::from_cell(macroquad::math::UVec2::new(1, 1), 1), Index(1)),
+        (ZorderPath::from_cell(macroquad::math::UVec2::new(7, 7), 3), Index(0)),
+    ];
+    for round in 0 .. 2 {
+        batched_root = batched.set_nodes(batched_root, &changes[round ..]).unwrap();
+        for (path, leaf) in &changes[round ..] {
+            sequential_root = sequential.set_nodes(sequential_root, &[(*path, *leaf)]).unwrap();
+        }
+        assert_eq!(batched.save_object_binary(batched_root), sequential.save_object_binary(sequential_root));
+        assert!(batched.validate(&[batched_root]).is_valid());
+    }
+}
+
+#[test]
+fn wrap_and_crop_round_trip() {
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let mut root = graph.get_root(0, 2).unwrap();
+    root = graph.set_nodes(root, &[
+        (ZorderPath::from_cell(macroquad::math::UVec2::new(1, 2), 2), Index(1)),
+        (ZorderPath::from_cell(macroquad::math::UVec2::new(2, 1), 2), Index(2)),
+    ]).unwrap();
+    let original = root.pointer;
+    for quadrant in 0 .. 4 {
+        root = graph.wrap_root(root, quadrant, Index(3)).unwrap();
+        assert!(graph.validate(&[root]).is_valid());
+        root = graph.crop_root(root, quadrant).unwrap();
+        assert!(graph.validate(&[root]).is_valid());
+        assert_eq!((root.pointer, root.height), (original, 2));
+    }
+    // Padding the root on all sides then recentering gets it back
+    let mut padded = graph.get_root(0, 3).unwrap();
+    let centered:Vec<_> = graph.node(original).unwrap().children().into_iter().enumerate()
+        .map(|(slot, child)| (ZorderPath::root().step_down(slot as u32).step_down(slot as u32 ^ 0b11), child))
+        .collect();
+    padded = graph.set_nodes(padded, &centered).unwrap();
+    assert!(graph.trim_quadrant(padded, Index(0)).is_none());
+    padded = graph.recenter_root(padded, Index(0)).unwrap();
+    assert_eq!((padded.pointer, padded.height), (original, 2));
+    assert!(graph.validate(&[root, padded]).is_valid());
+    let leaf = graph.get_root(2, 0).unwrap();
+    let wrapped = graph.wrap_root(leaf, 1, Index(2)).unwrap();
+    assert_eq!(wrapped.pointer, Index(2));
+    assert!(graph.validate(&[root, padded, wrapped]).is_valid());
+}
+
+#[test]
+fn fit_grows_and_shrinks_around_the_center() {
+    use macroquad::math::UVec2;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let cells = |cells:&[(u32, u32)], block:usize, height:u32| -> Vec<(ZorderPath, Index)> {
+        cells.iter().map(|(x, y)| (ZorderPath::from_cell(UVec2::new(*x, *y), height), Index(block))).collect()
+    };
+    let mut root = graph.get_root(0, 2).unwrap();
+    root = graph.set_nodes(root, &cells(&[(0, 0)], 1, 2)).unwrap();
+    // A block in the corner pushes the root out a level, leaving it in the centered window
+    root = graph.fit_root(root, Index(0)).unwrap();
+    assert_eq!(root.height, 3);
+    assert!(graph.is_centered(root, Index(0)) && graph.fit_root(root, Index(0)).is_none());
+    let mut expected = graph.get_root(0, 3).unwrap();
+    expected = graph.set_nodes(expected, &cells(&[(2, 2)], 1, 3)).unwrap();
+    assert_eq!(root.pointer, expected.pointer);
+    // Once the blocks huddle around the center it shrinks back, but not so far that it would grow again
+    root = graph.set_nodes(root, &[cells(&[(2, 2)], 0, 3), cells(&[(3, 3), (4, 4)], 2, 3)].concat()).unwrap();
+    root = graph.fit_root(root, Index(0)).unwrap();
+    assert_eq!(root.height, 2);
+    assert!(graph.fit_root(root, Index(0)).is_none());
+    assert!(graph.validate(&[root, expected]).is_valid());
+}
+
+#[test]
+fn leaves_added_later_survive_saves_and_compaction() {
+    use macroquad::math::UVec2;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let mut root = graph.get_root(0, 2).unwrap();
+    root = graph.set_nodes(root, &[(ZorderPath::from_cell(UVec2::new(0, 0), 1), Index(1))]).unwrap();
+    // Registered after the graph holds nodes, so it can't sit right after the other leaves
+    let block = graph.add_leaf(());
+    let leaf = graph.leaf(block).unwrap();
+    assert!(graph.is_leaf(leaf) && *leaf != block);
+    root = graph.set_nodes(root, &[(ZorderPath::from_cell(UVec2::new(3, 2), 2), leaf)]).unwrap();
+    assert!(graph.validate(&[root]).is_valid());
+    let blocks = |graph:&SparseDirectedGraph<BasicNode>, root:ExternalPointer| -> Vec<usize> {
+        (0 .. 16).map(|cell| {
+            let steps = ZorderPath::from_cell(UVec2::new(cell % 4, cell / 4), 2).steps();
+            graph.block(graph.read(root, &steps).unwrap().pointer).unwrap()
+        }).collect()
+    };
+    let expected = blocks(&graph, root);
+    // A graph that never heard of the block picks it up while loading json,
+    // binary trees can only use blocks the graph already has
+    let mut other = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    assert!(other.load_object_binary(&graph.save_object_binary(root)).is_err());
+    let (tree, table) = tree_from_json::<BasicNode>(&graph.save_object_json(root), graph.leaf_count()).unwrap();
+    let loaded = other.import_forest(&[tree], &table, &[0, 1, 2, 3, 4]).unwrap()[0];
+    assert_eq!(other.leaf_count(), 5);
+    assert_eq!(blocks(&other, loaded), expected);
+    let loaded = other.load_object_binary(&graph.save_object_binary(root)).unwrap();
+    assert_eq!(blocks(&other, loaded), expected);
+    let compaction = graph.compact(&[root]);
+    assert_eq!(blocks(&graph, compaction.roots[0]), expected);
+    assert!(graph.validate(&compaction.roots).is_valid());
+}
+
+#[test]
+fn compaction_keeps_live_and_history_roots() {
+    use macroquad::math::UVec2;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let edit = |graph:&mut SparseDirectedGraph<BasicNode>, root:ExternalPointer, cell:UVec2, leaf:usize| -> ExternalPointer {
+        // Snapshots keep the old root alive, like EntityPool's history does
+        graph.retain(root.pointer);
+        graph.set_nodes(root, &[(ZorderPath::from_cell(cell, 3), Index(leaf))]).unwrap()
+    };
+    let blank = graph.get_root(0, 3).unwrap();
+    let history = edit(&mut graph, blank, UVec2::new(1, 2), 1);
+    let live = edit(&mut graph, history, UVec2::new(6, 5), 2);
+    let other = graph.get_root(0, 3).unwrap();
+    let other = graph.set_nodes(other, &[(ZorderPath::from_cell(UVec2::new(6, 5), 3), Index(2))]).unwrap();
+    // Nothing holds on to this one anymore, but it never got released
+    let leaked = graph.get_root(0, 3).unwrap();
+    graph.set_nodes(leaked, &[(ZorderPath::from_cell(UVec2::new(7, 0), 3), Index(3))]).unwrap();
+
+    let roots = [live, other, blank, history];
+    let saved:Vec<_> = roots.iter().map(|root| graph.save_object_binary(*root)).collect();
+    let live_before = graph.live_nodes();
+    let compaction = graph.compact(&roots);
+    assert_eq!(compaction.roots.len(), roots.len());
+    for (root, saved) in compaction.roots.iter().zip(&saved) { assert_eq!(&graph.save_object_binary(*root), saved) }
+    assert!(graph.validate(&compaction.roots).is_valid());
+    assert!(compaction.dropped_nodes > 0 && compaction.reclaimed_slots > 0);
+    assert_eq!(graph.live_nodes(), live_before - compaction.dropped_nodes);
+}
+
+#[test]
+fn validate_table_rejects_broken_tables() {
+    let table = |nodes:&[[usize; 4]]| -> Vec<BasicNode> {
+        (0 .. 2).map(|leaf| BasicNode::new([Index(leaf); 4]))
+            .chain(nodes.iter().map(|children| BasicNode::new(children.map(Index))))
+            .collect()
+    };
+    let root = |index:usize, height:u32| ExternalPointer::new(Index(index), height);
+    assert!(validate_table(&table(&[[0, 1, 0, 0], [2, 0, 1, 0]]), root(3, 2)).is_ok());
+    assert!(matches!(validate_table(&table(&[[0, 1, 0, 3], [2, 0, 0, 0]]), root(3, 2)), Err(LoadError::Cycle(_))));
+    assert!(matches!(validate_table(&table(&[[0, 1, 0, 9]]), root(2, 1)), Err(LoadError::DanglingChild { node: 2, child: 9 })));
+    assert!(matches!(validate_table(&table(&[[0, 1, 0, 0]]), root(5, 1)), Err(LoadError::DanglingRoot(5))));
+    assert!(matches!(validate_table(&table(&[[0, 1, 0, 0], [2, 0, 1, 0]]), root(3, 1)), Err(LoadError::HeightOverflow { height: 2, max: 1 })));
+    assert!(matches!(validate_table(&table(&[]), root(0, MAX_TREE_HEIGHT + 1)), Err(LoadError::HeightOverflow { .. })));
+}
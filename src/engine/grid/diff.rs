@@ -0,0 +1,50 @@
+use super::dag::{SparseDirectedGraph, GraphNode, ExternalPointer, Index};
+use super::partition::ZorderPath;
+
+/// A quadrant that is a single leaf in both trees, holding different leaves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub path: ZorderPath,
+    pub old: Index,
+    pub new: Index,
+}
+
+impl<T: GraphNode> SparseDirectedGraph<T> {
+    /// Every region that differs between two trees of the same height, or None if the heights differ.
+    /// Feeding the paths and new leaves to set_nodes turns old into new.
+    pub fn diff(&self, old:ExternalPointer, new:ExternalPointer) -> Option<Vec<Change>> {
+        if old.height != new.height { return None }
+        let mut changes = Vec::new();
+        let mut stack = vec![(old.pointer, new.pointer, ZorderPath::root())];
+        while let Some((old, new, path)) = stack.pop() {
+            // Hash consing means equal indices are equal subtrees
+            if old == new { continue }
+            if self.is_leaf(old) && self.is_leaf(new) {
+                changes.push(Change { path, old, new });
+                continue
+            }
+            let (old_kids, new_kids) = (self.node(old).unwrap().children(), self.node(new).unwrap().children());
+            for step in (0 .. 4).rev() { stack.push((old_kids[step], new_kids[step], path.step_down(step as u32))) }
+        }
+        Some(changes)
+    }
+}
+
+#[test]
+fn diff_replays_onto_old() {
+    use macroquad::math::UVec2;
+    use super::dag::BasicNode;
+    use super::region::RegionEdit;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let old = graph.get_root(0, 3).unwrap();
+    graph.retain(old.pointer);
+    let mut new = graph.edit_rect(old, UVec2::new(2, 1), UVec2::new(5, 6), 0, RegionEdit::Fill(Index(3))).unwrap();
+    new = graph.set_nodes(new, &[(ZorderPath::from_cell(UVec2::new(7, 7), 3), Index(1))]).unwrap();
+    let changes = graph.diff(old, new).unwrap();
+    assert!(changes.iter().all(|change| change.old == Index(0) && change.new != Index(0)));
+    assert!(graph.diff(new, new).unwrap().is_empty());
+    let updates:Vec<_> = changes.iter().map(|change| (change.path, change.new)).collect();
+    let replayed = graph.set_nodes(old, &updates).unwrap();
+    assert_eq!(replayed.pointer, new.pointer);
+    assert!(graph.validate(&[new, replayed]).is_valid());
+}
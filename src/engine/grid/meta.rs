@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+use super::dag::{Node, GraphNode, Index, MAX_TREE_HEIGHT};
+
+// solid_area of a node that's solid all the way through, every split divides evenly until MAX_TREE_HEIGHT
+pub const FULL_AREA: u64 = 1 << (2 * MAX_TREE_HEIGHT);
+
+/// A node caching what its subtree holds, so walks can skip the parts they don't care about.
+/// The payload is rebuilt from the children, so saves only store the children.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MetaNode {
+    children : [Index; 4],
+    // Share of the node's area that's solid, out of FULL_AREA
+    #[serde(skip)]
+    solid_area : u64,
+    // Bit n is set when block n appears below, blocks past 63 share the last bit
+    #[serde(skip)]
+    materials : u64,
+}
+impl MetaNode {
+    pub fn solid_area(&self) -> u64 { self.solid_area }
+    pub fn has_solid(&self) -> bool { self.solid_area != 0 }
+    pub fn materials(&self) -> u64 { self.materials }
+}
+impl PartialEq for MetaNode {
+    fn eq(&self, other:&Self) -> bool { self.children == other.children }
+}
+impl Eq for MetaNode {}
+impl std::hash::Hash for MetaNode {
+    fn hash<H: std::hash::Hasher>(&self, state:&mut H) { self.children.hash(state) }
+}
+impl Node for MetaNode {
+    fn new(children:[Index; 4]) -> Self { Self { children, solid_area: 0, materials: 0 } }
+    fn children(&self) -> [Index; 4] { self.children }
+}
+impl GraphNode for MetaNode {
+    // Whether the block is solid
+    type LeafPayload = bool;
+    fn new_leaf(index:Index, block:usize, solid:bool) -> Self {
+        Self {
+            children: [index; 4],
+            solid_area: if solid { FULL_AREA } else { 0 },
+            materials: 1 << block.min(63),
+        }
+    }
+
+    fn refresh(&mut self, children:[&Self; 4]) {
+        self.solid_area = children.iter().map(|child| child.solid_area).sum::<u64>() / 4;
+        self.materials = children.iter().fold(0, |materials, child| materials | child.materials);
+    }
+}
+
+#[test]
+fn payloads_follow_edits() {
+    use macroquad::math::UVec2;
+    use super::dag::SparseDirectedGraph;
+    use super::partition::ZorderPath;
+    use super::region::RegionEdit;
+    // Blocks 1 and 3 are solid
+    let mut graph = SparseDirectedGraph::<MetaNode>::with_leaves(&[false, true, false, true]);
+    let mut root = graph.get_root(0, 2).unwrap();
+    root = graph.edit_rect(root, UVec2::new(0, 0), UVec2::new(1, 1), 0, RegionEdit::Fill(Index(1))).unwrap();
+    root = graph.set_nodes(root, &[(ZorderPath::from_cell(UVec2::new(3, 3), 2), Index(2))]).unwrap();
+    let node = graph.node(root.pointer).unwrap();
+    assert_eq!(node.solid_area(), FULL_AREA / 4);
+    assert_eq!(node.materials(), 0b111);
+    // Filling the last quadrant one cell at a time collapses it into a leaf along the way
+    for cell in [UVec2::new(3, 3), UVec2::new(2, 3), UVec2::new(3, 2), UVec2::new(2, 2)] {
+        root = graph.set_nodes(root, &[(ZorderPath::from_cell(cell, 2), Index(3))]).unwrap()
+    }
+    let node = graph.node(root.pointer).unwrap();
+    assert_eq!(node.solid_area(), FULL_AREA / 2);
+    assert_eq!(node.materials(), 0b1011);
+    assert!(graph.validate(&[root]).is_valid());
+}
+
+#[test]
+fn pasting_a_tree_into_itself_stays_acyclic() {
+    use macroquad::math::UVec2;
+    use super::dag::SparseDirectedGraph;
+    use super::partition::ZorderPath;
+    let mut graph = SparseDirectedGraph::<MetaNode>::with_leaves(&[false, true]);
+    let mut root = graph.get_root(0, 2).unwrap();
+    root = graph.set_nodes(root, &[(ZorderPath::from_cell(UVec2::new(0, 0), 2), Index(1))]).unwrap();
+    let before = graph.save_object_binary(root);
+    // Rewriting the root in place here would make it its own grandchild, so the copy has to get a fresh parent
+    let nested = graph.set_nodes(root, &[(ZorderPath::root().step_down(3), root.pointer)]).unwrap();
+    assert_ne!(nested.pointer, root.pointer);
+    assert_eq!(graph.save_object_binary(root), before);
+    assert_eq!(graph.node(nested.pointer).unwrap().solid_area(), FULL_AREA / 16 + FULL_AREA / 64);
+    assert!(graph.validate(&[nested]).is_valid());
+}
+
+#[test]
+fn late_leaf_payloads_reach_their_parents() {
+    use macroquad::math::UVec2;
+    use super::dag::{SparseDirectedGraph, tree_from_json};
+    use super::partition::ZorderPath;
+    let mut graph = SparseDirectedGraph::<MetaNode>::with_leaves(&[false, true]);
+    // Loading a table with a third leaf gives it a payload before anyone knows what it is
+    let mut source = SparseDirectedGraph::<MetaNode>::with_leaves(&[false, true, false]);
+    let root = source.get_root(0, 1).unwrap();
+    let root = source.set_nodes(root, &[(ZorderPath::from_cell(UVec2::new(0, 0), 1), Index(2))]).unwrap();
+    let (tree, table) = tree_from_json::<MetaNode>(&source.save_object_json(root), 3).unwrap();
+    let loaded = graph.import_forest(&[tree], &table, &[0, 1, 2]).unwrap()[0];
+    assert!(!graph.node(loaded.pointer).unwrap().has_solid());
+    graph.set_leaf_payload(2, true);
+    assert_eq!(graph.node(loaded.pointer).unwrap().solid_area(), FULL_AREA / 4);
+    assert!(graph.validate(&[loaded]).is_valid());
+}
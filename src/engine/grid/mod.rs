@@ -1,3 +1,11 @@
 pub mod dag;
 pub mod partition;
+pub mod binary;
+pub mod validation;
+pub mod region;
+pub mod clipboard;
+pub mod transform;
+pub mod csg;
+pub mod diff;
+pub mod meta;
 
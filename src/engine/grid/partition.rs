@@ -1,13 +1,14 @@
 use macroquad::math::{Vec2, IVec2, UVec2};
 use crate::engine::math::{Aabb, FloatUtils};
-use crate::engine::grid::dag::ExternalPointer;
+use crate::engine::grid::dag::{ExternalPointer, Node};
+use crate::engine::grid::meta::FULL_AREA;
 use crate::engine::entities::Location;
 use crate::globals::GRAPH;
 //Value loosely tuned to prevent both phasing and catching on corners
 //Used to sample area around a point to determine what cell(s) it's in
 pub const LIM_OFFSET: f32 = 2. / 0xFFFF as f32;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct ZorderPath {
     pub zorder : u32,
     pub depth : u32
@@ -86,16 +87,86 @@ impl ZorderPath {
         steps
     }
 
-    #[allow(dead_code)]
-    pub fn cells_intersecting_aabb(_aabb:Aabb, _max_depth: u32) -> Vec<Self> {
-        todo!()
+    /// Inclusive range of the cells at depth this path covers
+    pub fn cell_bounds(&self, depth:u32) -> (UVec2, UVec2) {
+        if self.depth >= depth {
+            let cell = self.with_depth(depth).to_cell();
+            return (cell, cell)
+        }
+        let scale = 1 << (depth - self.depth);
+        let corner = self.to_cell() * scale;
+        (corner, corner + (scale - 1))
+    }
+
+    /// The largest aligned quadrants exactly covering the inclusive rectangle of cells at depth
+    pub fn quadrants_in_rect(min:UVec2, max:UVec2, depth:u32) -> Vec<Self> {
+        let mut quadrants = Vec::new();
+        let mut stack = vec![Self::root()];
+        while let Some(path) = stack.pop() {
+            let (low, high) = path.cell_bounds(depth);
+            if low.cmpgt(max).any() || high.cmplt(min).any() { continue }
+            if low.cmpge(min).all() && high.cmple(max).all() { quadrants.push(path) }
+            else { for step in 0 .. 4 { stack.push(path.step_down(step)) } }
+        }
+        quadrants
+    }
+
+    /// The fewest cells no deeper than max_depth covering where the tree overlaps aabb, given in grid-local coordinates
+    /// (the top left of the root is (0, 0)). Cells only touching the box's edge are left out
+    pub fn cells_intersecting_aabb(start:ExternalPointer, min_cell_length:Vec2, aabb:Aabb, max_depth:u32, filter:CellFilter) -> Vec<Self> {
+        Self::cells_overlapping(start, min_cell_length, max_depth, filter, |cell| {
+            aabb.interiors_overlap(cell).then(|| cell.corners().iter().all(|corner| aabb.contains(*corner).all()))
+        })
+    }
+
+    /// cells_intersecting_aabb for aabb turned by rotation around its center, like another entity's bounds brought into this grid
+    pub fn cells_intersecting_rotated_aabb(start:ExternalPointer, min_cell_length:Vec2, aabb:Aabb, rotation:f32, max_depth:u32, filter:CellFilter) -> Vec<Self> {
+        let to_box = |point:Vec2| (point - aabb.center()).rotate(Vec2::from_angle(-rotation)) + aabb.center();
+        let from_box = |point:Vec2| (point - aabb.center()).rotate(Vec2::from_angle(rotation)) + aabb.center();
+        let box_in_grid = Aabb::from_points(aabb.corners().map(from_box));
+        Self::cells_overlapping(start, min_cell_length, max_depth, filter, |cell| {
+            // Separating axes, the box has to overlap the cell along the grid's axes and the cell has to overlap the box along its own
+            let cell_in_box = cell.corners().map(to_box);
+            let overlaps = box_in_grid.interiors_overlap(cell) && aabb.interiors_overlap(Aabb::from_points(cell_in_box));
+            overlaps.then(|| cell_in_box.iter().all(|corner| aabb.contains(*corner).all()))
+        })
     }
 
+    // Splits cells the query partly covers until they're leaves or max_depth deep.
+    // coverage gives None for cells it misses and whether it covers the whole cell otherwise
+    fn cells_overlapping(start:ExternalPointer, min_cell_length:Vec2, max_depth:u32, filter:CellFilter, coverage:impl Fn(Aabb) -> Option<bool>) -> Vec<Self> {
+        let graph = GRAPH.read();
+        let mut cells = Vec::new();
+        let mut stack = vec![(start.pointer, Self::root())];
+        while let Some((pointer, path)) = stack.pop() {
+            let node = graph.node(pointer).unwrap();
+            if filter == CellFilter::Occupied && !node.has_solid() { continue }
+            let length = cell_length(start.height - path.depth, min_cell_length);
+            let top_left = path.to_cell().as_vec2() * length;
+            let Some(covered) = coverage(Aabb::from_bounds(top_left, top_left + length)) else { continue };
+            // Partly empty cells aren't occupied all the way through, so they get split to leave the empty parts out
+            let whole = filter == CellFilter::All || node.solid_area() == FULL_AREA;
+            if graph.is_leaf(pointer) || path.depth >= max_depth || (covered && whole) { cells.push(path) }
+            else { for (step, child) in node.children().into_iter().enumerate() { stack.push((child, path.step_down(step as u32))) } }
+        }
+        cells
+    }
+
+}
+
+/// Which cells region queries return
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellFilter {
+    All,
+    // Cells with anything solid in them
+    Occupied,
 }
 
 #[derive(Debug, Clone, Copy, derive_new::new)]
 pub struct CellData {
     pub pointer : ExternalPointer,
+    // Block of the leaf pointer lands on
+    pub block : usize,
     pub cell : UVec2,
 }
 impl CellData {
@@ -152,9 +223,10 @@ pub mod gate {
     /// Only works if cell is at height 0
     pub fn find_real_cell(start:ExternalPointer, cell:UVec2) -> CellData {
         let path = ZorderPath::from_cell(cell, start.height);
-        let pointer = GRAPH.read().read(start, &path.steps()).unwrap();
+        let graph = GRAPH.read();
+        let pointer = graph.read(start, &path.steps()).unwrap();
         let zorder = path.with_depth(start.height - pointer.height);
-        CellData::new(pointer, zorder.to_cell())
+        CellData::new(pointer, graph.block(pointer.pointer).unwrap(), zorder.to_cell())
     }
 
 }
@@ -165,8 +237,8 @@ impl<T> super::dag::SparseDirectedGraph<T> where T : super::dag::GraphNode {
         let mut stack = Vec::from([(start.pointer, ZorderPath::root())]);
         let mut leaves = Vec::new();
         while let Some((pointer, zorder)) = stack.pop() {
-            if self.is_leaf(pointer) {
-                leaves.push(CellData::new(ExternalPointer::new(pointer, start.height - zorder.depth), zorder.to_cell()));
+            if let Some(block) = self.block(pointer) {
+                leaves.push(CellData::new(ExternalPointer::new(pointer, start.height - zorder.depth), block, zorder.to_cell()));
             } else { for i in 0 .. 4 {
                     let children = self.node(pointer).unwrap().children();
                     stack.push((children[i], zorder.step_down(i as u32)));
@@ -177,3 +249,34 @@ impl<T> super::dag::SparseDirectedGraph<T> where T : super::dag::GraphNode {
     }
 }
 
+
+#[test]
+fn aabb_queries_follow_the_tree() {
+    let root = {
+        let mut graph = GRAPH.write();
+        let blank = graph.get_root(0, 2).unwrap();
+        let grass = graph.leaf(1).unwrap();
+        let changes = [UVec2::new(0, 0), UVec2::new(1, 0)].map(|cell| (ZorderPath::from_cell(cell, 2), grass));
+        graph.set_nodes(blank, &changes).unwrap()
+    };
+    let query = |aabb, filter| {
+        let mut cells = ZorderPath::cells_intersecting_aabb(root, Vec2::ONE, aabb, 2, filter);
+        cells.sort_by_key(|path| (path.depth, path.zorder));
+        cells
+    };
+    let top_left = Aabb::from_bounds(Vec2::ZERO, Vec2::splat(2.));
+    // The top left quadrant is covered whole, but only half of it is occupied
+    assert_eq!(query(top_left, CellFilter::All), vec![ZorderPath::from_cell(UVec2::ZERO, 1)]);
+    assert_eq!(query(top_left, CellFilter::Occupied), vec![ZorderPath::from_cell(UVec2::new(0, 0), 2), ZorderPath::from_cell(UVec2::new(1, 0), 2)]);
+    // The bottom right quadrant is one empty leaf
+    let bottom_right = Aabb::new(Vec2::splat(2.5), Vec2::splat(0.1));
+    assert_eq!(query(bottom_right, CellFilter::All), vec![ZorderPath::from_cell(UVec2::ONE, 1)]);
+    assert!(query(bottom_right, CellFilter::Occupied).is_empty());
+
+    // A bar lying across the second row only reaches the grass once stood upright
+    let bar = Aabb::new(Vec2::new(0.5, 2.), Vec2::new(1.9, 0.4));
+    assert!(ZorderPath::cells_intersecting_aabb(root, Vec2::ONE, bar, 2, CellFilter::Occupied).is_empty());
+    let upright = ZorderPath::cells_intersecting_rotated_aabb(root, Vec2::ONE, bar, std::f32::consts::FRAC_PI_2, 2, CellFilter::Occupied);
+    assert_eq!(upright, vec![ZorderPath::from_cell(UVec2::ZERO, 2)]);
+    GRAPH.write().release(root.pointer);
+}
@@ -0,0 +1,132 @@
+use std::collections::{HashSet, VecDeque};
+use macroquad::math::{UVec2, IVec2};
+use vec_mem_heap::prelude::AccessError;
+use super::dag::{SparseDirectedGraph, GraphNode, ExternalPointer, Index};
+use super::partition::ZorderPath;
+
+#[derive(Debug, Clone, Copy)]
+pub enum RegionEdit {
+    Fill(Index),
+    // Takes the blank leaf, which only the caller knows
+    Clear(Index),
+    Replace { from: Index, to: Index },
+}
+
+impl<T: GraphNode> SparseDirectedGraph<T> {
+    /// Changes editing the inclusive rectangle of cells at height, merged into the largest aligned quadrants possible
+    pub fn rect_changes(&self, start:ExternalPointer, min:UVec2, max:UVec2, height:u32, edit:RegionEdit) -> Vec<(ZorderPath, Index)> {
+        let depth = start.height - height;
+        match edit {
+            RegionEdit::Fill(leaf) | RegionEdit::Clear(leaf) => ZorderPath::quadrants_in_rect(min, max, depth).into_iter().map(|path| (path, leaf)).collect(),
+            RegionEdit::Replace { from, to } => {
+                let mut changes = Vec::new();
+                let mut stack = vec![(start.pointer, ZorderPath::root())];
+                while let Some((node, path)) = stack.pop() {
+                    let (low, high) = path.cell_bounds(depth);
+                    if low.cmpgt(max).any() || high.cmplt(min).any() { continue }
+                    if self.is_leaf(node) {
+                        if node != from { continue }
+                        if low.cmpge(min).all() && high.cmple(max).all() {
+                            changes.push((path, to));
+                            continue
+                        }
+                    }
+                    let children = self.node(node).unwrap().children();
+                    for (step, child) in children.into_iter().enumerate() { stack.push((child, path.step_down(step as u32))) }
+                }
+                changes
+            }
+        }
+    }
+
+    pub fn edit_rect(&mut self, start:ExternalPointer, min:UVec2, max:UVec2, height:u32, edit:RegionEdit) -> Result<ExternalPointer, AccessError> {
+        if height > start.height { return Err(AccessError::OperationFailed) }
+        let changes = self.rect_changes(start, min, max, height, edit);
+        self.set_nodes(start, &changes)
+    }
+
+    /// Changes repainting the 4-connected region of leaves sharing the seed's block, the seed being any path inside the first leaf
+    pub fn flood_changes(&self, start:ExternalPointer, seed:ZorderPath, to:Index) -> Vec<(ZorderPath, Index)> {
+        let Some(pointer) = self.read(start, &seed.steps()) else { return Vec::new() };
+        let (seed, from) = (seed.with_depth(start.height - pointer.height), pointer.pointer);
+        if !self.is_leaf(from) || from == to { return Vec::new() }
+        let mut visited = HashSet::from([seed]);
+        let mut queue = VecDeque::from([seed]);
+        let mut neighbours = Vec::new();
+        while let Some(path) = queue.pop_front() {
+            neighbours.clear();
+            self.neighbouring_leaves(start, path, &mut neighbours);
+            for (neighbour, leaf) in neighbours.drain(..) {
+                if leaf == from && visited.insert(neighbour) { queue.push_back(neighbour) }
+            }
+        }
+        visited.into_iter().map(|path| (path, to)).collect()
+    }
+
+    pub fn flood_fill(&mut self, start:ExternalPointer, seed:ZorderPath, to:Index) -> Result<ExternalPointer, AccessError> {
+        let changes = self.flood_changes(start, seed, to);
+        self.set_nodes(start, &changes)
+    }
+
+    // Every leaf sharing an edge with the leaf at path, whatever their heights
+    fn neighbouring_leaves(&self, start:ExternalPointer, path:ZorderPath, neighbours:&mut Vec<(ZorderPath, Index)>) {
+        // Offsets paired with the children of a same sized neighbour that touch our shared edge
+        const SIDES: [(IVec2, [u32; 2]); 4] = [
+            (IVec2::new(-1, 0), [1, 3]),
+            (IVec2::new(1, 0), [0, 2]),
+            (IVec2::new(0, -1), [2, 3]),
+            (IVec2::new(0, 1), [0, 1]),
+        ];
+        for (offset, touching) in SIDES {
+            let Some(adjacent) = path.move_cartesianly(offset) else { continue };
+            let Some(pointer) = self.read(start, &adjacent.steps()) else { continue };
+            // A bigger neighbour is found further up, a smaller one means searching down its touching edge
+            let mut stack = vec![(pointer.pointer, adjacent.with_depth(start.height - pointer.height))];
+            while let Some((node, cur_path)) = stack.pop() {
+                if self.is_leaf(node) {
+                    neighbours.push((cur_path, node));
+                    continue
+                }
+                let children = self.node(node).unwrap().children();
+                for step in touching { stack.push((children[step as usize], cur_path.step_down(step))) }
+            }
+        }
+    }
+}
+
+#[test]
+fn rect_fill_matches_cellwise_fill() {
+    use super::dag::BasicNode;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let blank = graph.get_root(0, 3).unwrap();
+    graph.retain(blank.pointer);
+    let (min, max) = (UVec2::new(1, 0), UVec2::new(6, 5));
+    // Six 2x2 quadrants plus the twelve single cells in columns 1 and 6
+    assert_eq!(graph.rect_changes(blank, min, max, 0, RegionEdit::Fill(Index(2))).len(), 18);
+    let filled = graph.edit_rect(blank, min, max, 0, RegionEdit::Fill(Index(2))).unwrap();
+    let mut cellwise = blank;
+    for x in min.x ..= max.x {
+        for y in min.y ..= max.y {
+            cellwise = graph.set_nodes(cellwise, &[(ZorderPath::from_cell(UVec2::new(x, y), 3), Index(2))]).unwrap();
+        }
+    }
+    assert_eq!(filled.pointer, cellwise.pointer);
+    assert!(graph.validate(&[filled, cellwise]).is_valid());
+    let replaced = graph.edit_rect(filled, UVec2::ZERO, UVec2::splat(7), 0, RegionEdit::Replace { from: Index(2), to: Index(0) }).unwrap();
+    assert_eq!(replaced.pointer, Index(0));
+}
+
+#[test]
+fn flood_fill_stops_at_walls() {
+    use super::dag::BasicNode;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let mut root = graph.get_root(1, 2).unwrap();
+    // A wall down column 1 leaves single cells on the left and a mix of heights on the right
+    root = graph.edit_rect(root, UVec2::new(1, 0), UVec2::new(1, 3), 0, RegionEdit::Fill(Index(2))).unwrap();
+    root = graph.flood_fill(root, ZorderPath::from_cell(UVec2::new(3, 3), 2), Index(3)).unwrap();
+    let mut expected = graph.get_root(1, 2).unwrap();
+    expected = graph.edit_rect(expected, UVec2::new(1, 0), UVec2::new(1, 3), 0, RegionEdit::Fill(Index(2))).unwrap();
+    expected = graph.edit_rect(expected, UVec2::new(2, 0), UVec2::new(3, 3), 0, RegionEdit::Fill(Index(3))).unwrap();
+    assert_eq!(root.pointer, expected.pointer);
+    assert!(graph.validate(&[root, expected]).is_valid());
+}
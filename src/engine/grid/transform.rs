@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use super::dag::{SparseDirectedGraph, GraphNode, ExternalPointer, Index};
+
+// Rotations are clockwise as drawn, with y pointing down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    MirrorX,
+    MirrorY,
+    Transpose,
+}
+impl Transform {
+    /// The slot a child moves to, slots have x in bit 0 and y in bit 1
+    pub fn permute(self, slot:usize) -> usize {
+        match self {
+            Self::Rotate90 => [1, 3, 0, 2][slot],
+            Self::Rotate180 => slot ^ 0b11,
+            Self::Rotate270 => [2, 0, 3, 1][slot],
+            Self::MirrorX => slot ^ 0b01,
+            Self::MirrorY => slot ^ 0b10,
+            Self::Transpose => [0, 2, 1, 3][slot],
+        }
+    }
+
+    pub fn inverse(self) -> Self {
+        match self {
+            Self::Rotate90 => Self::Rotate270,
+            Self::Rotate270 => Self::Rotate90,
+            other => other,
+        }
+    }
+}
+
+impl<T: GraphNode> SparseDirectedGraph<T> {
+    /// Returns a transformed copy of the tree which holds its own references, start is left untouched
+    pub fn transform(&mut self, start:ExternalPointer, transform:Transform) -> ExternalPointer {
+        let mut memo = HashMap::new();
+        let root = self.transform_node(start.pointer, transform, &mut memo);
+        self.retain(root);
+        ExternalPointer::new(root, start.height)
+    }
+
+    // Shared subtrees are only transformed once thanks to memo
+    fn transform_node(&mut self, node:Index, transform:Transform, memo:&mut HashMap<Index, Index>) -> Index {
+        if self.is_leaf(node) { return node }
+        if let Some(transformed) = memo.get(&node) { return *transformed }
+        let children = self.node(node).unwrap().children();
+        let mut new_children = [Index(0); 4];
+        for (slot, child) in children.into_iter().enumerate() {
+            new_children[transform.permute(slot)] = self.transform_node(child, transform, memo);
+        }
+        let transformed = self.find_or_add(T::new(new_children));
+        memo.insert(node, transformed);
+        transformed
+    }
+}
+
+#[test]
+fn transforms_round_trip() {
+    use macroquad::math::UVec2;
+    use super::dag::BasicNode;
+    use super::partition::ZorderPath;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let mut root = graph.get_root(0, 3).unwrap();
+    for (x, y, leaf) in [(0, 0, 1), (1, 0, 2), (5, 2, 3), (7, 6, 1)] {
+        root = graph.set_nodes(root, &[(ZorderPath::from_cell(UVec2::new(x, y), 3), Index(leaf))]).unwrap();
+    }
+    let rotated = graph.transform(root, Transform::Rotate90);
+    // The top left cell ends up in the top right after a clockwise turn
+    assert_eq!(graph.read(rotated, &ZorderPath::from_cell(UVec2::new(7, 0), 3).steps()).unwrap().pointer, Index(1));
+    assert_eq!(graph.read(rotated, &ZorderPath::from_cell(UVec2::new(7, 1), 3).steps()).unwrap().pointer, Index(2));
+    let mut roots = vec![root, rotated];
+    for transform in [Transform::Rotate90, Transform::Rotate180, Transform::Rotate270, Transform::MirrorX, Transform::MirrorY, Transform::Transpose] {
+        let there = graph.transform(root, transform);
+        let back = graph.transform(there, transform.inverse());
+        assert_eq!(back.pointer, root.pointer);
+        roots.extend([there, back]);
+    }
+    assert!(graph.validate(&roots).is_valid());
+}
@@ -0,0 +1,132 @@
+use super::dag::{SparseDirectedGraph, GraphNode, ExternalPointer, Index};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    // A live node index_lookup doesn't know about
+    Unhashed(Index),
+    // index_lookup sends the node to another slot
+    LookupMismatch { index: Index, lookup: Index },
+    // index_lookup points at a freed slot or a slot holding something else
+    StaleLookup(Index),
+    // Expected counts one reference per parent for every way that parent is reached from a root, plus one per root and the node's own
+    RefCount { index: Index, expected: usize, found: usize },
+    // Four identical leaf children should have collapsed into that leaf
+    Uncollapsed(Index),
+    FreedChild { node: Index, child: Index },
+    Cycle(Index),
+}
+
+#[derive(Debug, Default)]
+pub struct GraphReport {
+    pub live_nodes: usize,
+    pub violations: Vec<Violation>,
+}
+impl GraphReport {
+    pub fn is_valid(&self) -> bool { self.violations.is_empty() }
+}
+
+impl<T: GraphNode> SparseDirectedGraph<T> {
+    /// Walks the whole node field and index_lookup, checking them against each other and against the roots.
+    /// Every root holding references has to be passed in, otherwise its nodes show up as ref count mismatches.
+    ///
+    /// A node's ref count is its parents plus the external roots pointing at it, where a parent that is itself
+    /// reached along several paths counts once per path (shared subtrees are counted as if they were unshared),
+    /// plus the one reference every node starts with. That's what retain and release add and remove.
+    pub fn validate(&self, roots:&[ExternalPointer]) -> GraphReport {
+        let mut report = GraphReport::default();
+        let slots = self.nodes.internal_memory().len();
+        for slot in 0 .. slots {
+            let index = Index(slot);
+            let Ok(node) = self.node(index) else { continue };
+            report.live_nodes += 1;
+            match self.index_lookup.get(node) {
+                Some(lookup) if *lookup == index => {}
+                Some(lookup) => report.violations.push(Violation::LookupMismatch { index, lookup: *lookup }),
+                None => report.violations.push(Violation::Unhashed(index)),
+            }
+            if self.is_leaf(index) { continue }
+            let children = node.children();
+            for child in children {
+                if self.node(child).is_err() { report.violations.push(Violation::FreedChild { node: index, child }) }
+            }
+            if self.is_leaf(children[0]) && children.iter().all(|child| *child == children[0]) {
+                report.violations.push(Violation::Uncollapsed(index));
+            }
+        }
+        for (node, index) in self.index_lookup.iter() {
+            if self.node(*index).ok() != Some(node) { report.violations.push(Violation::StaleLookup(*index)) }
+        }
+        let Some(order) = self.topological_order(slots, &mut report) else { return report };
+
+        // Each occurrence of a node in a root's expansion holds one reference, on top of the one the node starts with
+        let mut occurrences = vec![0usize; slots];
+        for root in roots {
+            if *root.pointer < slots { occurrences[*root.pointer] += 1 }
+        }
+        for index in order {
+            if self.is_leaf(index) { continue }
+            for child in self.node(index).unwrap().children() {
+                if *child < slots { occurrences[*child] = occurrences[*child].saturating_add(occurrences[*index]) }
+            }
+        }
+        for (slot, occurrences) in occurrences.into_iter().enumerate() {
+            let Ok(status) = self.nodes.status(Index(slot)) else { continue };
+            let found = status.get();
+            let expected = occurrences.saturating_add(1);
+            if found != expected { report.violations.push(Violation::RefCount { index: Index(slot), expected, found }) }
+        }
+        report
+    }
+
+    // Live nodes ordered parents first, or None if a cycle was found (and reported)
+    fn topological_order(&self, slots:usize, report:&mut GraphReport) -> Option<Vec<Index>> {
+        // 1 marks nodes on the current path and 2 marks finished nodes
+        let mut state = vec![0u8; slots];
+        let mut finished = Vec::new();
+        for slot in 0 .. slots {
+            if state[slot] != 0 || self.node(Index(slot)).is_err() { continue }
+            let mut stack = vec![(Index(slot), false)];
+            while let Some((index, done)) = stack.pop() {
+                if done {
+                    state[*index] = 2;
+                    finished.push(index);
+                    continue
+                }
+                if state[*index] != 0 { continue }
+                state[*index] = 1;
+                stack.push((index, true));
+                if self.is_leaf(index) { continue }
+                for child in self.node(index).unwrap().children() {
+                    if self.node(child).is_err() { continue }
+                    match state[*child] {
+                        0 => stack.push((child, false)),
+                        1 => {
+                            report.violations.push(Violation::Cycle(child));
+                            return None
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        finished.reverse();
+        Some(finished)
+    }
+}
+
+#[test]
+fn set_nodes_keeps_graph_valid() {
+    use super::dag::BasicNode;
+    use super::partition::ZorderPath;
+    let mut graph = SparseDirectedGraph::<BasicNode>::with_leaves(&[(); 4]);
+    let mut root = graph.get_root(0, 3).unwrap();
+    let mut seed = 12345u32;
+    for _ in 0 .. 200 {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let depth = (seed >> 8) % 4;
+        let path = (0 .. depth).fold(ZorderPath::root(), |path, layer| path.step_down((seed >> (12 + 2 * layer)) & 0b11));
+        root = graph.set_nodes(root, &[(path, Index((seed >> 24) as usize % 4))]).unwrap();
+        let report = graph.validate(&[root]);
+        assert!(report.is_valid(), "{:?}", report.violations);
+    }
+}
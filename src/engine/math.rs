@@ -14,6 +14,10 @@ impl Aabb {
             radius: (bottom_right - top_left) / 2.,
         }
     }
+    pub fn from_points(points:[Vec2; 4]) -> Self {
+        let (top_left, bottom_right) = points.iter().fold((Vec2::INFINITY, Vec2::NEG_INFINITY), |(min, max), point| (min.min(*point), max.max(*point)));
+        Self::from_bounds(top_left, bottom_right)
+    }
 
     pub fn min(&self) -> Vec2 { self.center - self.radius }
     pub fn max(&self) -> Vec2 { self.center + self.radius }
@@ -24,6 +28,14 @@ impl Aabb {
     pub fn intersects(&self, other:Self) -> BVec2 {
         (other.center - self.center).less_eq_mag(self.radius + other.radius)
     }
+    // Unlike intersects, boxes only sharing an edge don't count
+    pub fn interiors_overlap(&self, other:Self) -> bool {
+        (other.center - self.center).less_mag(self.radius + other.radius).all()
+    }
+    pub fn corners(&self) -> [Vec2; 4] {
+        let (top_left, bottom_right) = (self.min(), self.max());
+        [top_left, Vec2::new(bottom_right.x, top_left.y), Vec2::new(top_left.x, bottom_right.y), bottom_right]
+    }
     pub fn contains(&self, point:Vec2) -> BVec2 {
         (point - self.center).less_eq_mag(self.radius)
     }
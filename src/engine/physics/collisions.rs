@@ -3,9 +3,10 @@ use std::collections::{HashMap, BinaryHeap};
 use macroquad::color::*;
 use crate::globals::*;
 use macroquad::math::{Vec2, BVec2, IVec2};
-use crate::engine::grid::{partition::*, dag::{Index, ExternalPointer}};
+use crate::engine::grid::{partition::*, dag::ExternalPointer};
 use crate::engine::math::*;
-use crate::engine::entities::{Location, ID, Entity};
+use crate::engine::entities::{Location, ID, Entity, BodyType};
+use crate::engine::blocks::Material;
 use std::f32::consts::PI;
 
 #[derive(Debug, Clone, derive_new::new)]
@@ -37,6 +38,8 @@ impl CollisionObject {
 pub struct Particle {
     pub offset : Vec2,
     pub corner_type : CornerType,
+    // Block of the owner's cell this corner belongs to
+    pub block : usize,
     #[new(value = "0.")]
     pub ticks_into_projection : f32,
 }
@@ -145,47 +148,151 @@ impl CornerType {
 struct Hit {
     pub owner : ID,
     pub target : ID,
-    pub walls : BVec2,
+    // Where the owner touched, from the target's center in its own frame
+    pub point : Vec2,
+    // Out of the wall towards the owner, also in the target's frame
+    pub normal : Vec2,
+    pub material : Material,
+}
+
+// One side of a contact, as far as the impulse is concerned
+#[derive(Debug, Clone, Copy)]
+struct ContactBody {
+    // Of the body's material at the contact point
+    velocity : Vec2,
+    // From the center of mass to the contact point
+    arm : Vec2,
+    // From the grid's center to the center of mass
+    center_of_mass : Vec2,
+    inverse_mass : f32,
+    inverse_inertia : f32,
+}
+impl ContactBody {
+    fn new(entity:&Entity, contact:Vec2) -> Self {
+        // Entities store the velocity of their grid's center, but respond to impulses around their center of mass
+        let center_of_mass = entity.mass.center_of_mass.rotate(entity.forward);
+        let mass_velocity = entity.velocity + angular_to_tangential_velocity(entity.angular_velocity, center_of_mass);
+        let arm = contact - entity.location.position - center_of_mass;
+        // Only dynamic bodies get pushed around
+        let dynamic = entity.body_type == BodyType::Dynamic;
+        Self {
+            velocity: mass_velocity + angular_to_tangential_velocity(entity.angular_velocity, arm),
+            arm,
+            center_of_mass,
+            inverse_mass: if dynamic { entity.mass.inverse_mass() } else { 0. },
+            inverse_inertia: if dynamic { entity.mass.inverse_inertia() } else { 0. },
+        }
+    }
+
+    // How much the contact point gives way to a unit impulse along direction
+    fn inverse_effective_mass(&self, direction:Vec2) -> f32 {
+        self.inverse_mass + self.arm.perp_dot(direction).powi(2) * self.inverse_inertia
+    }
+
+    fn apply(&self, entity:&mut Entity, impulse:Vec2) {
+        let spin = self.arm.perp_dot(impulse) * self.inverse_inertia;
+        // The impulse pushes the center of mass, the grid's center also swings around it with the new spin
+        let velocity = impulse * self.inverse_mass - angular_to_tangential_velocity(spin, self.center_of_mass);
+        entity.velocity = (entity.velocity + velocity).snap_zero();
+        entity.angular_velocity = (entity.angular_velocity + spin).snap_zero();
+    }
 }
 
 // Eventually turn this into an island generator
-fn collect_collision_objects() -> Vec<CollisionObject> {
+fn collect_collision_objects(tick_max:f32) -> Vec<CollisionObject> {
     let mut objects = Vec::new();
     let entities = ENTITIES.read();
-    for idx in 0..entities.entities.len() {
-        let owner = &entities.entities[idx];
-        for other_idx in idx + 1..entities.entities.len() {
-            let target = &entities.entities[other_idx];
-            if let Some(obj) = entity_to_collision_object(owner, target) { 
-                objects.push(obj); 
-            }
-            if let Some(obj) = entity_to_collision_object(target, owner) { 
-                objects.push(obj); 
-            }
+    // Entities without a single solid cell can't hit or be hit, so skip them without walking their trees
+    let bounds:Vec<Option<Aabb>> = {
+        let graph = GRAPH.read();
+        entities.entities.iter().map(|entity| {
+            if !graph.node(entity.location.pointer.pointer).unwrap().has_solid() { return None }
+            entity.swept_aabb(tick_max)
+        }).collect()
+    };
+    for (idx, other_idx) in overlapping_pairs(&bounds) {
+        let (owner, target) = (&entities.entities[idx], &entities.entities[other_idx]);
+        // Neither side would respond to the hit
+        if owner.body_type != BodyType::Dynamic && target.body_type != BodyType::Dynamic { continue }
+        if !could_touch(owner, target, tick_max) || !could_touch(target, owner, tick_max) { continue }
+        if let Some(obj) = entity_to_collision_object(owner, target) { 
+            objects.push(obj); 
+        }
+        if let Some(obj) = entity_to_collision_object(target, owner) { 
+            objects.push(obj); 
         }
     }
     objects
 }
 
+// How deep could_touch looks into trees, deeper finds more misses but walks more of the tree
+const MIDPHASE_DEPTH: u32 = 4;
+
+// Whether mover's reach over the tick overlaps anything solid in target, always true while target spins since its cells sweep too
+fn could_touch(mover:&Entity, target:&Entity, tick_max:f32) -> bool {
+    if target.angular_velocity != 0. { return true }
+    let Some(reach) = mover.swept_aabb(0.) else { return false };
+    // Only the mover's box needs to sweep when it moves relative to the target
+    let reach = reach.expand((mover.velocity - target.velocity) * tick_max);
+    let unrotate = Vec2::from_angle(-target.rotation);
+    let center = (reach.center() - target.location.position).rotate(unrotate) + center_to_edge(target.location.pointer.height, target.location.min_cell_length);
+    let cells = ZorderPath::cells_intersecting_rotated_aabb(
+        target.location.pointer,
+        target.location.min_cell_length,
+        Aabb::new(center, reach.radius()),
+        -target.rotation,
+        MIDPHASE_DEPTH,
+        CellFilter::Occupied,
+    );
+    !cells.is_empty()
+}
+
+// Sweep and prune along x, giving each pair of overlapping bounds once with the lower index first
+fn overlapping_pairs(bounds:&[Option<Aabb>]) -> Vec<(usize, usize)> {
+    let mut order:Vec<(usize, Aabb)> = bounds.iter().enumerate().filter_map(|(idx, aabb)| Some((idx, (*aabb)?))).collect();
+    order.sort_by(|(_, a), (_, b)| a.min().x.total_cmp(&b.min().x));
+    let mut active:Vec<(usize, Aabb)> = Vec::new();
+    let mut pairs = Vec::new();
+    for (idx, aabb) in order {
+        // Anything ending before this starts ends before everything after it starts too
+        active.retain(|(_, other)| other.max().x.greater_eq(aabb.min().x));
+        for (other_idx, other) in active.iter() {
+            if aabb.intersects(*other).all() { pairs.push((idx.min(*other_idx), idx.max(*other_idx))) }
+        }
+        active.push((idx, aabb));
+    }
+    // Keeps the order objects are found in the same as checking every pair did
+    pairs.sort_unstable();
+    pairs
+}
+
+// Air resistance on everything that moves, whether or not it's touching something.
+// Damping at contacts comes from the friction and restitution of the blocks involved instead.
 fn apply_drag() {
     const DRAG_MULTIPLIER: f32 = 0.95;
-    for entity in &mut ENTITIES.write().entities { 
+    for entity in ENTITIES.write().entities.iter_mut().filter(|entity| entity.body_type == BodyType::Dynamic) {
         entity.velocity = (entity.velocity * DRAG_MULTIPLIER).snap_zero();
         entity.angular_velocity = (entity.angular_velocity * DRAG_MULTIPLIER).snap_zero();
     }
 }
 
 fn tick_entities(delta_tick: f32) {
-    for entity in &mut ENTITIES.write().entities {
+    for entity in ENTITIES.write().entities.iter_mut().filter(|entity| entity.body_type != BodyType::Static) {
         entity.location.position += (entity.velocity * delta_tick).snap_zero();
         entity.rel_rotate((entity.angular_velocity * delta_tick).snap_zero());
     }
 }
 
-pub fn n_body_collisions(static_thing: ID) {
+// Static bodies can still be given velocity, it just never gets to do anything
+fn settle_static() {
+    for entity in ENTITIES.write().entities.iter_mut().filter(|entity| entity.body_type == BodyType::Static) { entity.stop() }
+}
+
+pub fn n_body_collisions() {
+    settle_static();
     let mut tick_max = 1.;
     loop {
-        let objects = collect_collision_objects();
+        let objects = collect_collision_objects(tick_max);
         let (actions, ticks_at_hit) = find_next_action(objects, tick_max);
         
         if actions.is_empty() {
@@ -196,29 +303,53 @@ pub fn n_body_collisions(static_thing: ID) {
             tick_entities(ticks_at_hit);
         }
         
+        // Touching several blocks at once pushes through the middle of the contacts, with the bounciest of them and the average grip
         let mut combined_hits = HashMap::new();
         for hit in &actions {
-            *combined_hits.entry((hit.owner, hit.target)).or_insert(hit.walls) |= hit.walls;
+            let (point, normal, material, count) = combined_hits.entry((hit.owner, hit.target)).or_insert((Vec2::ZERO, Vec2::ZERO, hit.material, 0.));
+            *point += hit.point;
+            *normal += hit.normal;
+            material.restitution = material.restitution.max(hit.material.restitution);
+            if *count > 0. { material.friction += hit.material.friction }
+            *count += 1.;
         }
         let mut entities = ENTITIES.write();
-        for ((owner_id, target_id), walls) in &combined_hits {
-            let target = entities.get_entity(*target_id).unwrap();
-            let rel_velocity = entities.get_entity(*owner_id).unwrap().velocity - target.velocity;
-            let world_impulse = (-rel_velocity.rotate(Vec2::from_angle(-target.rotation)) * walls.as_vec2()).rotate(target.forward);
-            let changes = [(owner_id, 1.), (target_id, -1.)];
-            for (entity_id, multiplier) in changes {
-                if *entity_id != static_thing {
-                    let entity = entities.get_mut_entity(*entity_id).unwrap();
-                    entity.velocity = (entity.velocity + world_impulse * multiplier).snap_zero();
-                    entity.angular_velocity = 0.;
-                }
-            }
+        for ((owner_id, target_id), (point, normal, mut material, count)) in combined_hits {
+            material.friction /= count;
+            // Walls facing each other cancel out, leaving nothing to push along
+            let Some(normal) = normal.try_normalize() else { continue };
+            let (owner, target) = (entities.get_entity(owner_id).unwrap(), entities.get_entity(target_id).unwrap());
+            // Everything has been ticked up to the hit, so the target's current rotation is the one the hit was found in
+            let contact = (point / count).rotate(target.forward) + target.location.position;
+            let (owner_body, target_body) = (ContactBody::new(owner, contact), ContactBody::new(target, contact));
+            if owner_body.inverse_mass + target_body.inverse_mass == 0. { continue }
+            let impulse = contact_impulse(owner_body, target_body, normal.rotate(target.forward), material);
+            owner_body.apply(entities.get_mut_entity(owner_id).unwrap(), impulse);
+            target_body.apply(entities.get_mut_entity(target_id).unwrap(), -impulse);
         }
     }
     apply_drag();
 }
 
+// Resting contacts bouncing off each other every tick would jitter forever
+const BOUNCE_THRESHOLD: f32 = 0.01;
+
+// Impulse on the owner at the contact, the target gets the opposite so momentum and angular momentum are kept
+fn contact_impulse(owner:ContactBody, target:ContactBody, normal:Vec2, material:Material) -> Vec2 {
+    let rel_velocity = owner.velocity - target.velocity;
+    let approach = rel_velocity.dot(normal);
+    if approach.greater_eq(0.) { return Vec2::ZERO }
+    let restitution = if approach.abs().less(BOUNCE_THRESHOLD) { 0. } else { material.restitution };
+    let normal_impulse = -approach * (1. + restitution) / (owner.inverse_effective_mass(normal) + target.inverse_effective_mass(normal));
+    // Coulomb friction, it can stop sliding but never reverse it
+    let sliding = rel_velocity - normal * approach;
+    let Some(tangent) = sliding.try_normalize() else { return normal * normal_impulse };
+    let stopping_impulse = sliding.length() / (owner.inverse_effective_mass(tangent) + target.inverse_effective_mass(tangent));
+    normal * normal_impulse - tangent * stopping_impulse.min(material.friction * normal_impulse)
+}
+
 pub fn _just_move() {
+    settle_static();
     tick_entities(1.);
     apply_drag();
 }
@@ -250,16 +381,22 @@ fn find_next_action(objects:Vec<CollisionObject>, tick_max:f32) -> (Vec<Hit>, f3
             cur_corner.ticks_into_projection += ticks_to_hit;
             cur_corner.offset = motion.project_to(ticks_to_hit) - object.projected_owner(cur_corner.ticks_into_projection);
             cur_corner.corner_type = cur_corner.corner_type.rotate(ticks_to_hit * (object.owner_angular - object.target_angular));
-            if let Some(walls_hit) = hitting_wall(
-                gate::point_to_real_cells(object.target_location, motion.project_to(ticks_to_hit)),
-                object.instant_tangential_velocity(cur_corner.offset, cur_corner.ticks_into_projection),
+            let contact = motion.project_to(ticks_to_hit);
+            let itvel = object.instant_tangential_velocity(cur_corner.offset, cur_corner.ticks_into_projection);
+            if let Some((walls_hit, wall_block)) = hitting_wall(
+                gate::point_to_real_cells(object.target_location, contact),
+                itvel,
                 cur_corner.corner_type
             ) {
                 if cur_corner.ticks_into_projection.less(ticks_to_action) { action.clear() }
+                let blocks = BLOCKS.read();
                 action.push( Hit {
                     owner : object.owner,
                     target : object.target,
-                    walls : walls_hit,
+                    point : contact - object.target_location.position,
+                    // Hit walls face against the velocity that hit them
+                    normal : (-walls_hit.as_vec2() * itvel.signum()).normalize(),
+                    material : blocks.material(cur_corner.block).mix(blocks.material(wall_block)),
                 } );
                 ticks_to_action = cur_corner.ticks_into_projection;
             } else { object.particles.push(Reverse(cur_corner)) }
@@ -315,7 +452,8 @@ pub fn entity_to_collision_object(owner:&Entity, target:&Entity) -> Option<Colli
                 .rotate(align_target) + target.location.position - rotated_owner_pos;
             collision_points.push(Reverse(Particle::new(
                 offset,
-                CornerType::from_index(i).rotate(owner.rotation - target.rotation)
+                CornerType::from_index(i).rotate(owner.rotation - target.rotation),
+                corners.block,
             )));
         }
     }
@@ -335,7 +473,7 @@ pub fn entity_to_collision_object(owner:&Entity, target:&Entity) -> Option<Colli
 #[derive(Debug, Clone, derive_new::new)]
 pub struct Corners {
     pub points : [Vec2; 4],
-    pub index : Index,
+    pub block : usize,
     pub mask : u8,
 }
 
@@ -351,6 +489,8 @@ pub mod corner_handling {
             ([(IVec2::new(1, 0), 0b10), (IVec2::new(0, 1), 0b01), (IVec2::new(1, 1), 0b00)], 0b1000),    // Bottom Right
         ];
 
+        let graph = GRAPH.read();
+        let blocks = BLOCKS.read();
         let mut exposed_mask = 0b0000;
         'corner: for (checks, mask) in CORNER_CHECKS {
             for (offset, direction) in checks {
@@ -358,8 +498,8 @@ pub mod corner_handling {
                 for _ in 0 .. start.height - check_zorder.depth {
                     check_zorder = check_zorder.step_down(direction as u32)
                 }
-                let pointer = GRAPH.read().read(start, &check_zorder.steps()).unwrap();
-                if BLOCKS.is_solid_index(*pointer.pointer) { continue 'corner }
+                let pointer = graph.read(start, &check_zorder.steps()).unwrap();
+                if blocks.is_solid_index(graph.block(pointer.pointer).unwrap()) { continue 'corner }
             }
             exposed_mask |= mask;
         }
@@ -385,8 +525,8 @@ pub mod corner_handling {
             let zorder = ZorderPath::from_cell(cell.cell, start.height - cell.pointer.height);
             corners.push( Corners::new(
                 cell_corners(cell, min_cell_length),
-                cell.pointer.pointer,
-                if !BLOCKS.is_solid_index(*cell.pointer.pointer) { 0 } else { cell_corner_mask(start, zorder) }
+                cell.block,
+                if !BLOCKS.read().is_solid_index(cell.block) { 0 } else { cell_corner_mask(start, zorder) }
             ));
         }
         corners 
@@ -395,13 +535,17 @@ pub mod corner_handling {
     
 }
 
-fn hitting_wall(position_data:[Option<CellData>; 4], itvel:Vec2, corner_type:CornerType) -> Option<BVec2> {
+// The walls hit and the block of the cell they belong to
+fn hitting_wall(position_data:[Option<CellData>; 4], itvel:Vec2, corner_type:CornerType) -> Option<(BVec2, usize)> {
     let mut hit_walls = corner_type.hittable_walls(itvel);
     // If we're inside air cells, we should override the wall check.
-    hit_walls &= BVec2::splat(match corner_type.checks(itvel) {
-        CheckZorders::One(idx) => BLOCKS.is_solid_cell(position_data[idx]),
-        CheckZorders::Two([idx1, idx2]) => BLOCKS.is_solid_cell(position_data[idx1]) | BLOCKS.is_solid_cell(position_data[idx2]),
-    });
+    let blocks = BLOCKS.read();
+    let checked = match corner_type.checks(itvel) {
+        CheckZorders::One(idx) => [idx, idx],
+        CheckZorders::Two(idxs) => idxs,
+    };
+    let wall = checked.into_iter().filter_map(|idx| position_data[idx]).find(|cell| blocks.is_solid_cell(Some(*cell)));
+    hit_walls &= BVec2::splat(wall.is_some());
     // If we're on a wall, check if we should be sliding or stopping.
     if hit_walls == BVec2::TRUE {
         let idxs = match itvel.signum() {
@@ -412,12 +556,115 @@ fn hitting_wall(position_data:[Option<CellData>; 4], itvel:Vec2, corner_type:Cor
             _ => unreachable!(),
         };
         let slide = BVec2::new(
-            BLOCKS.is_solid_cell(position_data[idxs[0]]),
-            BLOCKS.is_solid_cell(position_data[idxs[1]])
+            blocks.is_solid_cell(position_data[idxs[0]]),
+            blocks.is_solid_cell(position_data[idxs[1]])
         );
         // If we're on a corner (both air), we should still stop
         if slide != BVec2::FALSE { hit_walls &= slide }
     };
     // None represents no walls nicer than BVec::FALSE does imo
-    (hit_walls != BVec2::FALSE).then_some(hit_walls)
+    (hit_walls != BVec2::FALSE).then(|| (hit_walls, wall.unwrap().block))
+}
+
+#[test]
+fn contact_impulse_follows_materials() {
+    let material = |friction, restitution| Material { density: 1., friction, restitution };
+    // A unit mass landing on an immovable floor at its center of mass while sliding right
+    let velocity = Vec2::new(0.5, 1.);
+    let owner = ContactBody { velocity, arm: Vec2::ZERO, center_of_mass: Vec2::ZERO, inverse_mass: 1., inverse_inertia: 1. };
+    let floor = ContactBody { velocity: Vec2::ZERO, arm: Vec2::ZERO, center_of_mass: Vec2::ZERO, inverse_mass: 0., inverse_inertia: 0. };
+    let up = Vec2::new(0., -1.);
+    let bounced = velocity + contact_impulse(owner, floor, up, material(0.9, 0.8));
+    assert!(bounced.y.approx_eq(-0.8));
+    let slid = velocity + contact_impulse(owner, floor, up, material(0.02, 0.));
+    assert!(slid.y.approx_eq(0.) && slid.x.approx_eq(0.48));
+    let stuck = velocity + contact_impulse(owner, floor, up, material(1., 0.));
+    assert!(stuck.approx_eq(Vec2::ZERO));
+}
+
+#[test]
+fn glancing_contact_induces_spin() {
+    let material = Material { density: 1., friction: 0., restitution: 0. };
+    // Two equal bodies, the owner falling onto the target with its contact right of both centers of mass, so they spin opposite ways
+    let owner = ContactBody { velocity: Vec2::new(0., 1.), arm: Vec2::new(1., 0.5), center_of_mass: Vec2::ZERO, inverse_mass: 1., inverse_inertia: 2. };
+    let target = ContactBody { velocity: Vec2::ZERO, arm: Vec2::new(1., -0.5), center_of_mass: Vec2::ZERO, inverse_mass: 1., inverse_inertia: 2. };
+    let impulse = contact_impulse(owner, target, Vec2::new(0., -1.), material);
+    let spins = [(owner, impulse), (target, -impulse)].map(|(body, impulse)| body.arm.perp_dot(impulse) * body.inverse_inertia);
+    assert!(!spins[0].is_zero() && spins[0].approx_eq(-spins[1]));
+    // The contact points stop closing in on each other without passing through
+    let contact_velocity = |body:ContactBody, impulse:Vec2, spin:f32| body.velocity + impulse * body.inverse_mass + angular_to_tangential_velocity(spin, body.arm);
+    let closing = contact_velocity(owner, impulse, spins[0]) - contact_velocity(target, -impulse, spins[1]);
+    assert!(closing.y.abs() < 1e-5);
+}
+
+#[test]
+fn contacts_conserve_momentum_around_off_center_masses() {
+    use super::mass::grass_column;
+    // The center of mass sits half a cell left of the grid's center
+    let mut owner = Entity::new(0, Location::new(Vec2::new(0.2, -2.), grass_column()), 0.3);
+    owner.velocity = Vec2::new(0.5, 2.);
+    owner.angular_velocity = 1.;
+    let mut target = Entity::new(1, Location::new(Vec2::ZERO, grass_column()), -0.2);
+    target.angular_velocity = -0.5;
+    // Linear momentum, and angular momentum around the origin
+    let momentum = |entities:[&Entity; 2]| entities.iter().fold((Vec2::ZERO, 0.), |(linear, angular), entity| {
+        let center_of_mass = entity.mass.center_of_mass.rotate(entity.forward);
+        let velocity = entity.velocity + angular_to_tangential_velocity(entity.angular_velocity, center_of_mass);
+        let orbit = (entity.location.position + center_of_mass).perp_dot(velocity) * entity.mass.mass;
+        (linear + velocity * entity.mass.mass, angular + orbit + entity.mass.inertia * entity.angular_velocity)
+    });
+    let before = momentum([&owner, &target]);
+    let contact = Vec2::new(-0.8, -1.);
+    let material = Material { density: 1., friction: 0.5, restitution: 0.5 };
+    let (owner_body, target_body) = (ContactBody::new(&owner, contact), ContactBody::new(&target, contact));
+    let impulse = contact_impulse(owner_body, target_body, Vec2::new(0., -1.), material);
+    assert!(impulse.length() > 0.1);
+    owner_body.apply(&mut owner, impulse);
+    target_body.apply(&mut target, -impulse);
+    let after = momentum([&owner, &target]);
+    assert!(before.0.distance(after.0) < 1e-4 && (before.1 - after.1).abs() < 1e-4);
+    for entity in [owner, target] { GRAPH.write().release(entity.location.pointer.pointer) }
+}
+
+#[test]
+fn midphase_skips_boxes_over_empty_cells() {
+    use super::mass::grass_column;
+    // Solid down the left half of a grid centered on the origin
+    let mut target = Entity::new(0, Location::new(Vec2::ZERO, grass_column()), 0.);
+    let cell = GRAPH.write().get_root(1, 0).unwrap();
+    let mut mover = Entity::new(1, Location::new(Vec2::new(0.8, 0.), cell), 0.);
+    // The mover only reaches over the empty right half
+    assert!(!could_touch(&mover, &target, 1.));
+    mover.velocity = Vec2::new(-1., 0.);
+    assert!(could_touch(&mover, &target, 1.));
+    // Turned around, the solid half is the one on the right
+    mover.velocity = Vec2::ZERO;
+    target.rel_rotate(PI);
+    assert!(could_touch(&mover, &target, 1.));
+    target.rel_rotate(PI);
+    target.angular_velocity = 1.;
+    assert!(could_touch(&mover, &target, 1.));
+    for entity in [target, mover] { GRAPH.write().release(entity.location.pointer.pointer) }
+}
+
+#[test]
+fn broad_phase_matches_every_pair() {
+    let bounds = [
+        Some(Aabb::new(Vec2::new(0., 0.), Vec2::splat(1.))),
+        Some(Aabb::new(Vec2::new(1.5, 0.5), Vec2::splat(1.))),
+        None,
+        // Overlaps the first two along x but not y
+        Some(Aabb::new(Vec2::new(1., 5.), Vec2::splat(1.))),
+        Some(Aabb::new(Vec2::new(10., 0.), Vec2::splat(1.))),
+        Some(Aabb::new(Vec2::new(2.5, 0.), Vec2::new(0.5, 1.))),
+    ];
+    let mut expected = Vec::new();
+    for idx in 0 .. bounds.len() {
+        for other_idx in idx + 1 .. bounds.len() {
+            let (Some(a), Some(b)) = (bounds[idx], bounds[other_idx]) else { continue };
+            if a.intersects(b).all() { expected.push((idx, other_idx)) }
+        }
+    }
+    assert_eq!(overlapping_pairs(&bounds), expected);
+    assert_eq!(expected, vec![(0, 1), (1, 5)]);
 }
@@ -0,0 +1,66 @@
+use macroquad::math::Vec2;
+use crate::globals::{GRAPH, BLOCKS};
+use crate::engine::grid::dag::ExternalPointer;
+use crate::engine::grid::partition::{cell_length, center_to_edge};
+
+/// How an entity's solid cells resist being pushed and spun, in its own frame
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MassProperties {
+    pub mass : f32,
+    // Offset from the center of the grid
+    pub center_of_mass : Vec2,
+    // Moment of inertia about the center of mass
+    pub inertia : f32,
+}
+impl MassProperties {
+    /// Zero for anything that can't be moved by impulses
+    pub fn inverse_mass(&self) -> f32 {
+        if self.mass > 0. { 1. / self.mass } else { 0. }
+    }
+    pub fn inverse_inertia(&self) -> f32 {
+        if self.inertia > 0. { 1. / self.inertia } else { 0. }
+    }
+
+    /// Sums every solid leaf of the tree as a uniform rectangle of its block's density
+    pub fn from_tree(start:ExternalPointer, min_cell_length:Vec2) -> Self {
+        let leaves = GRAPH.read().dfs_leaf_cells(start);
+        let blocks = BLOCKS.read();
+        let grid_offset = center_to_edge(start.height, min_cell_length);
+        // (mass, center, inertia about its own center) for each cell
+        let cells:Vec<(f32, Vec2, f32)> = leaves.iter().filter(|cell| blocks.is_solid_index(cell.block)).map(|cell| {
+            let size = cell_length(cell.pointer.height, min_cell_length);
+            let mass = blocks.material(cell.block).density * size.x * size.y;
+            let center = cell.cell.as_vec2() * size + size / 2. - grid_offset;
+            (mass, center, mass * size.length_squared() / 12.)
+        }).collect();
+        let mass:f32 = cells.iter().map(|(mass, ..)| mass).sum();
+        if mass <= 0. { return Self::default() }
+        let center_of_mass = cells.iter().map(|(mass, center, _)| *center * *mass).sum::<Vec2>() / mass;
+        // Parallel axis theorem moves each cell's inertia onto the shared center
+        let inertia = cells.iter().map(|(mass, center, inertia)| inertia + mass * center.distance_squared(center_of_mass)).sum();
+        Self { mass, center_of_mass, inertia }
+    }
+}
+
+/// Grass down the left half of a 2 by 2 grid in the world graph, which the caller has to release
+#[cfg(test)]
+pub fn grass_column() -> ExternalPointer {
+    use macroquad::math::UVec2;
+    use crate::engine::grid::partition::ZorderPath;
+    let mut graph = GRAPH.write();
+    let blank = graph.get_root(0, 1).unwrap();
+    let grass = graph.leaf(1).unwrap();
+    let changes = [UVec2::new(0, 0), UVec2::new(0, 1)].map(|cell| (ZorderPath::from_cell(cell, 1), grass));
+    graph.set_nodes(blank, &changes).unwrap()
+}
+
+#[test]
+fn column_matches_solid_rectangle() {
+    let root = grass_column();
+    let mass = MassProperties::from_tree(root, Vec2::ONE);
+    // Grass has a density of 1, so this is a 1 by 2 rectangle of mass 2 left of center
+    assert_eq!(mass.mass, 2.);
+    assert_eq!(mass.center_of_mass, Vec2::new(-0.5, 0.));
+    assert!((mass.inertia - 2. * (1. + 4.) / 12.).abs() < 1e-5);
+    GRAPH.write().release(root.pointer);
+}
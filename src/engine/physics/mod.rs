@@ -1,2 +1,3 @@
 pub mod collisions;
-pub mod raymarching;
\ No newline at end of file
+pub mod raymarching;
+pub mod mass;
\ No newline at end of file
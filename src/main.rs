@@ -1,30 +1,39 @@
 mod engine;
 mod globals {
     use crate::engine::blocks::BlockPalette;
-    use crate::engine::grid::dag::{SparseDirectedGraph, BasicNode};
+    use crate::engine::grid::dag::SparseDirectedGraph;
+    use crate::engine::grid::meta::MetaNode;
     use crate::engine::camera::Camera;
     use macroquad::math::Vec2;
     use crate::engine::entities::EntityPool;
     use lazy_static::lazy_static;
     use parking_lot::RwLock;
     lazy_static! {
-        pub static ref GRAPH: RwLock<SparseDirectedGraph<BasicNode>> = RwLock::new(SparseDirectedGraph::<BasicNode>::new(4));
+        pub static ref GRAPH: RwLock<SparseDirectedGraph<MetaNode>> = RwLock::new(SparseDirectedGraph::<MetaNode>::with_leaves(&BLOCKS.read().solidity()));
         pub static ref ENTITIES: RwLock<EntityPool> = RwLock::new(EntityPool::new());
         pub static ref CAMERA: RwLock<Camera> = RwLock::new(Camera::new(Vec2::ZERO, 4.));
-        pub static ref BLOCKS: BlockPalette = BlockPalette::default();
+        // Locked after GRAPH when both are needed
+        pub static ref BLOCKS: RwLock<BlockPalette> = RwLock::new(BlockPalette::default());
     }
 }
 use globals::*;
 use engine::input::*;
+use engine::blocks::{BlockPalette, BLANK_BLOCK};
 use macroquad::math::Vec2;
-use macroquad::prelude::{mouse_position, KeyCode, MouseButton};
+use macroquad::prelude::{mouse_position, is_mouse_button_pressed, is_key_down, KeyCode, MouseButton};
 use std::f32::consts::PI;
 use engine::{
     physics::collisions::n_body_collisions,
-    entities::{Entity, ID, Location},
+    entities::{Entity, ID, Location, Normalization, BodyType},
     math::Aabb,
-    grid::dag::{Index, ExternalPointer},
+    grid::dag::ExternalPointer,
+    grid::meta::MetaNode,
+    grid::clipboard::Clipboard,
+    grid::transform::Transform,
+    grid::csg::CsgOp,
+    grid::diff::Change,
     grid::partition::{gate, ZorderPath},
+    grid::region::RegionEdit,
 };
 
 use std::time::Duration;
@@ -51,8 +60,8 @@ fn init_deadlock_detection() {
 
 const SPEED: f32 = 0.005;
 const ROTATION_SPEED: f32 = PI/512.;
-const MAX_COLOR: usize = 4;
 const MAX_HEIGHT: u32 = 4;
+const HIGHLIGHT_FRAMES: u32 = 90;
 
 fn set_panic_hook() {
     std::panic::set_hook(Box::new(|panic_info| {
@@ -78,6 +87,22 @@ fn set_panic_hook() {
 }
 
 fn mouse_pos() -> Vec2 { Vec2::from(mouse_position()) }
+fn shift_down() -> bool { is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift) }
+fn ctrl_down() -> bool { is_key_down(KeyCode::LeftControl) || is_key_down(KeyCode::RightControl) }
+
+// Holding shift while saving or loading an entity uses the binary file next to its json one
+fn entity_path(data:&InputData) -> String {
+    let extension = if shift_down() { "bin" } else { "json" };
+    format!("{}/entity_{}.{extension}", data.save_dir(), data.target_id)
+}
+
+fn load_or_blank(data:Vec<u8>, id:ID) -> Entity {
+    Entity::load_bytes(data, id).unwrap_or_else(|error| {
+        eprintln!("Failed to load entity {id}: {error}");
+        let root = GRAPH.write().get_root(BLANK_BLOCK, 0).unwrap();
+        Entity::new(id, Location::new(Vec2::ZERO, root), 0.)
+    })
+}
 use macroquad::color::*;
 
 #[macroquad::main("Window")]
@@ -91,24 +116,36 @@ async fn main() {
     #[cfg(not(debug_assertions))]
     println!("Release mode");
     macroquad::window::request_new_screen_size(1024., 1024.);
+    // Leaves read the palette when they're made, so it has to be in place before anything touches the graph
+    {
+        let palette_bytes = if cfg!(target_arch = "wasm32") {
+            include_bytes!("../data/blocks.json").to_vec()
+        } else {
+            std::fs::read("data/blocks.json").unwrap_or_default()
+        };
+        match BlockPalette::from_json(&palette_bytes) {
+            Ok(palette) => *BLOCKS.write() = palette,
+            Err(error) => eprintln!("Failed to load block palette, using the default one: {error}"),
+        }
+    }
     // Load entities 
     {
         let mut entity_pool = ENTITIES.write();
-        let terrain_string = if cfg!(target_arch = "wasm32") { 
-            String::from_utf8(include_bytes!("../data/terrain.json").as_ref().to_vec()).unwrap_or_default()
+        let terrain_bytes = if cfg!(target_arch = "wasm32") { 
+            include_bytes!("../data/terrain.json").to_vec()
         } else {
-            std::fs::read_to_string("data/terrain.json").unwrap_or_default()
+            std::fs::read("data/terrain.json").unwrap_or_default()
         };
         entity_pool.add_to_pool(
-            Entity::load(terrain_string, 0)
+            load_or_blank(terrain_bytes, 0)
         );
-        let player_string = if cfg!(target_arch = "wasm32") { 
-            String::from_utf8(include_bytes!("../data/player.json").as_ref().to_vec()).unwrap_or_default()
+        let player_bytes = if cfg!(target_arch = "wasm32") { 
+            include_bytes!("../data/player.json").to_vec()
         } else {
-            std::fs::read_to_string("data/player.json").unwrap_or_default()
+            std::fs::read("data/player.json").unwrap_or_default()
         };
         entity_pool.add_to_pool(
-            Entity::load(player_string, 1)
+            load_or_blank(player_bytes, 1)
         );
     }
     
@@ -121,6 +158,15 @@ async fn main() {
             entities.draw_all(vars.render_rotated, vars.render_debug);
             let target = entities.get_entity(vars.target_id()).unwrap();
             target.draw_outline(macroquad::color::DARKBLUE);
+            if let Some((id, changes, frames)) = &mut vars.highlight {
+                if let Some(entity) = entities.get_entity(*id) { entity.draw_changes(changes, macroquad::color::ORANGE) }
+                *frames -= 1;
+                if *frames == 0 { vars.highlight = None }
+            }
+            if let Some(start) = vars.select_start {
+                let end = CAMERA.read().screen_to_world(mouse_pos());
+                CAMERA.read().outline_bounds(Aabb::from_bounds(start.min(end), start.max(end)), 0.1, macroquad::color::YELLOW);
+            }
             // let location = entities.get_entity((vars.target_id() + 1) % 2).unwrap().location;
             // if let Some(aabb) = target.aabb() { 
             //     aabb.overlaps(location);
@@ -133,7 +179,7 @@ async fn main() {
         
         input.handle(&mut vars);
         
-        n_body_collisions((vars.target_id() + 1) % 2);
+        n_body_collisions();
         
         // We don't want to move the camera until after we've drawn all the collision debug.
         // This ensures everything lines up with the current frame.
@@ -169,22 +215,45 @@ pub fn set_grid_cell(entity:ID, world_point:Vec2, new_cell:ExternalPointer) {
     let entity = &mut entities.get_mut_entity(entity).unwrap();
     if new_cell.height > entity.location.pointer.height { return; }
     
-    let rotated_point = (world_point - entity.location.position).rotate(Vec2::from_angle(-entity.rotation)) + entity.location.position;
-    
-    let Some(cell) = gate::point_to_cells(entity.location, new_cell.height, rotated_point)[0] else { return };
+    let Some(cell) = entity.world_point_to_cell(world_point, new_cell.height) else { return };
     let path = ZorderPath::from_cell(cell, entity.location.pointer.height - new_cell.height);
-    let Ok(root) = GRAPH.write().set_node(entity.location.pointer, &path.steps(), new_cell.pointer) else {
+    let Ok(root) = GRAPH.write().set_nodes(entity.location.pointer, &[(path, new_cell.pointer)]) else {
         dbg!("Failed to set cell");
         return;
     };
-    entity.set_root(root);
+    entity.apply_edit(root);
+}
+
+pub fn fill_grid_rect(entity:ID, world_a:Vec2, world_b:Vec2, height:u32, edit:RegionEdit) {
+    let mut entities = ENTITIES.write();
+    let entity = &mut entities.get_mut_entity(entity).unwrap();
+    if height > entity.location.pointer.height { return; }
+    let (Some(cell_a), Some(cell_b)) = (entity.world_point_to_cell(world_a, height), entity.world_point_to_cell(world_b, height)) else { return };
+    entity.checkpoint();
+    entity.edit_rect(cell_a, cell_b, height, edit);
+}
+
+pub fn paste_clipboard(data:&InputData, op:Option<CsgOp>) {
+    let Some(clipboard) = &data.clipboard else { return };
+    let point = CAMERA.read().screen_to_world(mouse_pos());
+    let mut entities = ENTITIES.write();
+    let entity = entities.get_mut_entity(data.target_id).unwrap();
+    entity.checkpoint();
+    entity.paste_at(point, clipboard, op);
+}
+
+pub fn transform_clipboard(data:&mut InputData, transform:Transform) {
+    if let Some(clipboard) = &mut data.clipboard {
+        *clipboard = GRAPH.read().transform_clipboard(clipboard, transform);
+    }
 }
 
 pub trait DataAccess {
     fn target_id(&self) -> ID;
     fn edit_color(&self) -> usize;
     fn edit_height(&self) -> u32;
-    fn file_paths(&self) -> &[String; 2];
+    fn save_dir(&self) -> &str;
+    fn world_path(&self) -> &str;
 }
 pub struct InputData {
     pub target_id : ID,
@@ -192,7 +261,13 @@ pub struct InputData {
     pub edit_height : u32,
     pub render_debug : bool,
     pub render_rotated: bool,
-    pub file_paths : [String; 2],
+    // Entities are saved as entity_{id} inside this
+    pub save_dir : String,
+    pub world_path : String,
+    pub select_start : Option<Vec2>,
+    pub clipboard : Option<Clipboard<MetaNode>>,
+    // Entity, what changed in it and how many more frames to show that for
+    pub highlight : Option<(ID, Vec<Change>, u32)>,
 }
 impl Default for InputData {
     fn default() -> Self {
@@ -202,15 +277,27 @@ impl Default for InputData {
             edit_height: 0,
             render_debug: true,
             render_rotated: true,
-            file_paths: ["data/terrain.json".to_string(), "data/player.json".to_string()],
+            save_dir: "data".to_string(),
+            world_path: "data/world.json".to_string(),
+            select_start: None,
+            clipboard: None,
+            highlight: None,
         }
     }
 }
+impl InputData {
+    /// Highlights what differs between two roots of an entity for a while, if they're comparable
+    pub fn highlight_changes(&mut self, id:ID, old:ExternalPointer, new:ExternalPointer) {
+        self.highlight = GRAPH.read().diff(old, new).map(|changes| (id, changes, HIGHLIGHT_FRAMES));
+    }
+}
+
 impl DataAccess for InputData {
     fn target_id(&self) -> ID { self.target_id }
     fn edit_color(&self) -> usize { self.edit_color }
     fn edit_height(&self) -> u32 { self.edit_height }
-    fn file_paths(&self) -> &[String; 2] { &self.file_paths }
+    fn save_dir(&self) -> &str { &self.save_dir }
+    fn world_path(&self) -> &str { &self.world_path }
 }
 
 pub fn set_key_binds() -> InputHandler<InputData> {
@@ -247,43 +334,202 @@ pub fn set_key_binds() -> InputHandler<InputData> {
     // Editing
     input.bind_key(KeyCode::V, InputTrigger::Pressed, |data : &mut InputData| {
         let color = &mut data.edit_color;
-        *color = (*color + 1) % MAX_COLOR;
+        *color = (*color + 1) % BLOCKS.read().block_count();
     });
     input.bind_key(KeyCode::B, InputTrigger::Pressed, |data : &mut InputData| {
         let height = &mut data.edit_height;
         *height = (*height + 1) % MAX_HEIGHT;
     });
     input.bind_mouse(MouseButton::Left, InputTrigger::Down, |data : &mut InputData| {
+        // A whole stroke is one undo step
+        let Some(leaf) = GRAPH.read().leaf(data.edit_color) else { return };
+        if is_mouse_button_pressed(MouseButton::Left) {
+            ENTITIES.write().get_mut_entity(data.target_id).unwrap().checkpoint();
+        }
         set_grid_cell(
             data.target_id,
             CAMERA.read().screen_to_world(mouse_pos()),
-            ExternalPointer::new(Index(data.edit_color), data.edit_height)
+            ExternalPointer::new(leaf, data.edit_height)
+        );
+    });
+    // Dragging with the right mouse fills the box between press and release
+    input.bind_mouse(MouseButton::Right, InputTrigger::Pressed, |data : &mut InputData| {
+        data.select_start = Some(CAMERA.read().screen_to_world(mouse_pos()));
+    });
+    input.bind_mouse(MouseButton::Right, InputTrigger::Released, |data : &mut InputData| {
+        let Some(start) = data.select_start.take() else { return };
+        let Some(leaf) = GRAPH.read().leaf(data.edit_color) else { return };
+        fill_grid_rect(
+            data.target_id,
+            start,
+            CAMERA.read().screen_to_world(mouse_pos()),
+            data.edit_height,
+            RegionEdit::Fill(leaf)
         );
     });
+    input.bind_mouse(MouseButton::Middle, InputTrigger::Pressed, |data : &mut InputData| {
+        let Some(leaf) = GRAPH.read().leaf(data.edit_color) else { return };
+        let point = CAMERA.read().screen_to_world(mouse_pos());
+        let mut entities = ENTITIES.write();
+        let entity = entities.get_mut_entity(data.target_id).unwrap();
+        entity.checkpoint();
+        entity.flood_fill(point, leaf);
+    });
+    input.bind_key(KeyCode::C, InputTrigger::Pressed, |data : &mut InputData| {
+        let point = CAMERA.read().screen_to_world(mouse_pos());
+        data.clipboard = ENTITIES.read().get_entity(data.target_id).unwrap().copy_at(point, data.edit_height);
+    });
+    input.bind_key(KeyCode::X, InputTrigger::Pressed, |data : &mut InputData| {
+        paste_clipboard(data, None);
+    });
+    // U adds the clipboard, merging blocks or with shift only filling air
+    input.bind_key(KeyCode::U, InputTrigger::Pressed, |data : &mut InputData| {
+        let op = if shift_down() { CsgOp::Union } else { CsgOp::Merge };
+        paste_clipboard(data, Some(op));
+    });
+    // R carves the clipboard out, shift keeps only the overlap and ctrl toggles it
+    input.bind_key(KeyCode::R, InputTrigger::Pressed, |data : &mut InputData| {
+        let op = if ctrl_down() { CsgOp::Xor } else if shift_down() { CsgOp::Intersection } else { CsgOp::Difference };
+        paste_clipboard(data, Some(op));
+    });
+    // T turns the clipboard clockwise, shift turns it back and ctrl flips it over
+    input.bind_key(KeyCode::T, InputTrigger::Pressed, |data : &mut InputData| {
+        let transform = if ctrl_down() { Transform::Rotate180 }
+            else if shift_down() { Transform::Rotate90.inverse() }
+            else { Transform::Rotate90 };
+        transform_clipboard(data, transform);
+    });
+    // G mirrors across x, shift across y and ctrl across the diagonal
+    input.bind_key(KeyCode::G, InputTrigger::Pressed, |data : &mut InputData| {
+        let transform = if ctrl_down() { Transform::Transpose }
+            else if shift_down() { Transform::MirrorY }
+            else { Transform::MirrorX };
+        transform_clipboard(data, transform);
+    });
+    input.bind_key(KeyCode::N, InputTrigger::Pressed, |data : &mut InputData| {
+        let mut entities = ENTITIES.write();
+        let entity = entities.get_mut_entity(data.target_id).unwrap();
+        entity.normalization = match entity.normalization {
+            Normalization::Off => Normalization::Trim,
+            Normalization::Trim => Normalization::Fit,
+            Normalization::Fit => Normalization::Off,
+        };
+        println!("Normalization: {:?}", entity.normalization);
+    });
+    input.bind_key(KeyCode::Tab, InputTrigger::Pressed, |data : &mut InputData| {
+        let mut entities = ENTITIES.write();
+        let entity = entities.get_mut_entity(data.target_id).unwrap();
+        entity.body_type = match entity.body_type {
+            BodyType::Static => BodyType::Kinematic,
+            BodyType::Kinematic => BodyType::Dynamic,
+            BodyType::Dynamic => BodyType::Static,
+        };
+        println!("Body type: {:?}", entity.body_type);
+    });
+    input.bind_key(KeyCode::Z, InputTrigger::Pressed, |data : &mut InputData| {
+        let mut entities = ENTITIES.write();
+        let entity = entities.get_mut_entity(data.target_id).unwrap();
+        let old = entity.location.pointer;
+        if !entity.undo() { dbg!("Nothing to undo"); return; }
+        data.highlight_changes(data.target_id, old, entity.location.pointer);
+    });
+    input.bind_key(KeyCode::Y, InputTrigger::Pressed, |data : &mut InputData| {
+        let mut entities = ENTITIES.write();
+        let entity = entities.get_mut_entity(data.target_id).unwrap();
+        let old = entity.location.pointer;
+        if !entity.redo() { dbg!("Nothing to redo"); return; }
+        data.highlight_changes(data.target_id, old, entity.location.pointer);
+    });
+    // ] grows the grid a level toward the mouse, [ crops it to the quadrant under the mouse
+    input.bind_key(KeyCode::RightBracket, InputTrigger::Pressed, |data : &mut InputData| {
+        let point = CAMERA.read().screen_to_world(mouse_pos());
+        let mut entities = ENTITIES.write();
+        let entity = entities.get_mut_entity(data.target_id).unwrap();
+        entity.checkpoint();
+        let blank = GRAPH.read().leaf(BLANK_BLOCK).unwrap();
+        if !entity.grow_toward(point, blank) { dbg!("Grid is already as tall as it gets"); }
+    });
+    input.bind_key(KeyCode::LeftBracket, InputTrigger::Pressed, |data : &mut InputData| {
+        let point = CAMERA.read().screen_to_world(mouse_pos());
+        let mut entities = ENTITIES.write();
+        let entity = entities.get_mut_entity(data.target_id).unwrap();
+        entity.checkpoint();
+        if !entity.crop_toward(point) { dbg!("Grid is already a single cell"); }
+    });
     input.bind_key(KeyCode::F, InputTrigger::Pressed, |data : &mut InputData| {
-        ENTITIES.write().get_mut_entity(data.target_id).unwrap().stop();
-        data.target_id = (data.target_id + 1) % 2;
+        let mut entities = ENTITIES.write();
+        entities.get_mut_entity(data.target_id).unwrap().stop();
+        if let Some(next) = entities.next_id(data.target_id) { data.target_id = next }
     });
     
     // Save/Load
     if !cfg!(target_arch = "wasm32") {
         input.bind_key(KeyCode::K, InputTrigger::Pressed, |data : &mut InputData| {
-            let save_data = ENTITIES.read().save_entity(data.target_id);
-            std::fs::write(&data.file_paths[data.target_id as usize], save_data).unwrap();
+            let path = entity_path(data);
+            // Paths ending in .bin get the compact format, everything else stays json
+            let save_data = if path.ends_with(".bin") {
+                ENTITIES.read().get_entity(data.target_id).unwrap().save_binary()
+            } else {
+                ENTITIES.read().save_entity(data.target_id).into_bytes()
+            };
+            std::fs::write(path, save_data).unwrap();
         });
         input.bind_key(KeyCode::L, InputTrigger::Pressed, |data : &mut InputData| {
             let mut entities = ENTITIES.write();
-            let Ok(save_data) = std::fs::read_to_string(&data.file_paths[data.target_id as usize]) else {
+            let Ok(save_data) = std::fs::read(entity_path(data)) else {
                 dbg!("No save data found");
                 return;
             };
-            *entities.get_mut_entity(data.target_id).unwrap() = Entity::load(save_data, data.target_id)
+            match Entity::load_bytes(save_data, data.target_id) {
+                Ok(entity) => {
+                    let old = entities.get_mut_entity(data.target_id).unwrap();
+                    data.highlight_changes(data.target_id, old.location.pointer, entity.location.pointer);
+                    old.history.clear();
+                    GRAPH.write().release(old.location.pointer.pointer);
+                    *old = entity;
+                }
+                Err(error) => eprintln!("{error}"),
+            }
+        });
+        input.bind_key(KeyCode::J, InputTrigger::Pressed, |data : &mut InputData| {
+            let save_data = ENTITIES.read().save_world();
+            std::fs::write(data.world_path(), save_data).unwrap();
+        });
+        input.bind_key(KeyCode::H, InputTrigger::Pressed, |data : &mut InputData| {
+            let Ok(save_data) = std::fs::read_to_string(data.world_path()) else {
+                dbg!("No world found");
+                return;
+            };
+            let mut entities = ENTITIES.write();
+            // The old tree is held onto until the loaded one has been compared with it
+            let old = entities.get_entity(data.target_id).map(|entity| entity.location.pointer);
+            if let Some(old) = old { GRAPH.write().retain(old.pointer) }
+            let loaded = entities.load_world(&save_data);
+            if let Some(old) = old {
+                if loaded.is_ok() && let Some(entity) = entities.get_entity(data.target_id) {
+                    data.highlight_changes(data.target_id, old, entity.location.pointer);
+                }
+                GRAPH.write().release(old.pointer);
+            }
+            if let Err(error) = loaded {
+                eprintln!("{error}");
+                return;
+            }
+            if entities.get_entity(data.target_id).is_none() && let Some(first) = entities.entities.first() {
+                data.target_id = first.id;
+            }
         });
     }
 
     // Debug
     input.bind_key(KeyCode::P, InputTrigger::Pressed, |_data : &mut InputData| {
-        dbg!(GRAPH.read().nodes.internal_memory());
+        let roots = ENTITIES.read().live_roots();
+        let report = GRAPH.read().validate(&roots);
+        if report.is_valid() { println!("Graph valid with {} live nodes", report.live_nodes) } else { dbg!(report); }
+    });
+    input.bind_key(KeyCode::M, InputTrigger::Pressed, |_data : &mut InputData| {
+        let compaction = ENTITIES.write().compact_graph(&mut []);
+        println!("Compacted graph: {} slots reclaimed, {} unreachable nodes dropped", compaction.reclaimed_slots, compaction.dropped_nodes);
     });
     input.bind_key(KeyCode::O, InputTrigger::Pressed, |data : &mut InputData| {
         data.render_debug = !data.render_debug;